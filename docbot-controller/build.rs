@@ -1,28 +1,29 @@
-use docbot_crd::DeploymentHook;
+use docbot_crd::{ClusterDeploymentHook, DeploymentHook};
+use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1::CustomResourceDefinition;
 use kube::CustomResourceExt;
 use std::fs::File;
 use std::io::Write;
 use std::path::Path;
 
-fn main() {
-    let mut crd = DeploymentHook::crd();
-
+fn write_crd(mut crd: CustomResourceDefinition, file_name: &str) {
     // These fields show up as Some([]) in rust, but go will exclude because
     // they are zero values. If you see Argo continue to cause issues sync'ing,
     // check a compact diff and see if any new fields show up.
     crd.spec.names.categories = None;
     crd.spec.names.short_names = None;
-    crd.spec
-        .versions
-        .iter_mut()
-        .for_each(|version| version.additional_printer_columns = None);
 
     // Write to file.
     let schema = serde_yaml::to_string(&crd).unwrap();
     let crate_dir = std::env::var_os("CARGO_MANIFEST_DIR").unwrap();
-    let crd_schema_path = Path::new(&crate_dir)
-        .join("..")
-        .join("deploymenthooks.apps.mx.com.yaml");
+    let crd_schema_path = Path::new(&crate_dir).join("..").join(file_name);
     let mut f = File::create(&crd_schema_path).unwrap();
     f.write_all(schema.as_bytes()).unwrap();
 }
+
+fn main() {
+    write_crd(DeploymentHook::crd(), "deploymenthooks.apps.mx.com.yaml");
+    write_crd(
+        ClusterDeploymentHook::crd(),
+        "clusterdeploymenthooks.apps.mx.com.yaml",
+    );
+}
@@ -1,47 +1,104 @@
-use crate::utils::DeploymentExt;
-use docbot_crd::DeploymentHook;
-use k8s_openapi::api::apps::v1::Deployment;
+use crate::utils::WorkloadExt;
+use docbot_crd::{ClusterDeploymentHook, DeploymentHook, Hook, TargetKind, Workload};
+use k8s_openapi::api::core::v1::Namespace;
 use kube::{api::ListParams, client::Client, Api};
+use kube_runtime::reflector::{store::Writer, ObjectRef, Store};
 use std::collections::BTreeMap;
 use std::sync::{Arc, Mutex};
 
-#[derive(Default, Debug, Clone)]
-pub struct DeploymentHookCache {
-    cache: Arc<Mutex<BTreeMap<(String, String), DeploymentHook>>>,
+/// Every hook of kind `H`, kept in sync by the reflector of the hook watcher.
+#[derive(Debug, Clone)]
+pub struct HookCache<H: Hook> {
+    store: Store<H>,
 }
 
-impl DeploymentHookCache {
-    pub async fn refresh(&self, client: &Client) -> Result<(), Box<dyn std::error::Error>> {
-        let api: Api<DeploymentHook> = Api::all(client.clone());
-        let hooks: BTreeMap<(String, String), DeploymentHook> = api
-            .list(&ListParams::default())
-            .await?
-            .items
-            .iter()
-            .map(|hook| {
-                (
-                    (
-                        hook.metadata.namespace.clone().expect("must have a name"),
-                        hook.metadata.name.clone().expect("must have a namespace"),
-                    ),
-                    hook.clone(),
-                )
-            })
-            .collect();
+pub type DeploymentHookCache = HookCache<DeploymentHook>;
 
-        let mut cache = self.cache.lock().unwrap();
-        *cache = hooks;
-        Ok(())
+pub type ClusterDeploymentHookCache = HookCache<ClusterDeploymentHook>;
+
+impl<H: Hook> HookCache<H> {
+    /// An empty cache, along with the writer the hook watcher fills it through.
+    pub fn new() -> (Self, Writer<H>) {
+        let writer = Writer::default();
+        let store = writer.as_reader();
+
+        (Self { store }, writer)
     }
 
-    pub fn find_by_matching_deployment(&self, deployment: &Deployment) -> Vec<DeploymentHook> {
-        let cache = self.cache.lock().unwrap();
-        cache
-            .values()
-            .filter(|hook| hook.does_match_deployment(deployment))
-            .cloned()
+    #[cfg(feature = "metrics")]
+    pub fn len(&self) -> usize {
+        self.store.state().len()
+    }
+
+    pub fn all(&self) -> Vec<H> {
+        self.store
+            .state()
+            .into_iter()
+            .map(|hook| hook.as_ref().clone())
             .collect()
     }
+
+    /// Cluster scoped hooks are looked up with an empty namespace.
+    pub fn get(&self, namespace: &str, name: &str) -> Option<H> {
+        let reference = match namespace {
+            "" => ObjectRef::new(name),
+            namespace => ObjectRef::new(name).within(namespace),
+        };
+
+        self.store.get(&reference).map(|hook| hook.as_ref().clone())
+    }
+
+    pub fn find_by_matching_deployment(
+        &self,
+        deployment: &impl Workload,
+        namespace_labels: &BTreeMap<String, String>,
+    ) -> Vec<H> {
+        self.store
+            .state()
+            .into_iter()
+            .filter(|hook| hook.does_match_deployment(deployment, namespace_labels))
+            .map(|hook| hook.as_ref().clone())
+            .collect()
+    }
+}
+
+/// Every namespace, kept in sync by the reflector of the namespace watcher and used to evaluate
+/// the namespace selectors of hooks.
+#[derive(Debug, Clone)]
+pub struct NamespaceLabelCache {
+    store: Store<Namespace>,
+}
+
+impl NamespaceLabelCache {
+    /// An empty cache, along with the writer the namespace watcher fills it through.
+    pub fn new() -> (Self, Writer<Namespace>) {
+        let writer = Writer::default();
+        let store = writer.as_reader();
+
+        (Self { store }, writer)
+    }
+
+    /// Return the labels of a namespace, falling back to the API for namespaces the watcher
+    /// hasn't caught up with yet.
+    pub async fn get(
+        &self,
+        client: &Client,
+        namespace: &str,
+    ) -> Result<BTreeMap<String, String>, Box<dyn std::error::Error>> {
+        if let Some(cached) = self.store.get(&ObjectRef::new(namespace)) {
+            return Ok(cached.metadata.labels.clone().unwrap_or_default());
+        }
+
+        let api: Api<Namespace> = Api::all(client.clone());
+        let labels = api
+            .get(namespace)
+            .await?
+            .metadata
+            .labels
+            .unwrap_or_default();
+
+        Ok(labels)
+    }
 }
 
 pub enum CacheOp {
@@ -49,41 +106,95 @@ pub enum CacheOp {
     Unchanged,
 }
 
+/// Workloads are identified by their kind, namespace and name.
+type WorkloadKey = (TargetKind, String, String);
+
+/// The pod template hash last seen for every workload.
 #[derive(Default, Debug, Clone)]
 pub struct DeploymentPodTemplateHashCache {
-    cache: Arc<Mutex<BTreeMap<(String, String), String>>>,
+    cache: Arc<Mutex<BTreeMap<WorkloadKey, String>>>,
+    /// The generation of every deployment when it was last handled.
+    generations: Arc<Mutex<BTreeMap<WorkloadKey, i64>>>,
+}
+
+fn cache_key(deployment: &impl Workload) -> WorkloadKey {
+    (
+        deployment.target_kind(),
+        deployment.meta().namespace.clone().unwrap_or_default(),
+        deployment.meta().name.clone().unwrap_or_default(),
+    )
 }
 
 impl DeploymentPodTemplateHashCache {
-    pub async fn refresh(&self, client: &Client) -> Result<(), Box<dyn std::error::Error>> {
-        let api: Api<Deployment> = Api::all(client.clone());
+    /// Prime the cache with the deployments whose current rollout already finished. Rollouts
+    /// still in progress trigger hooks once they finish.
+    pub async fn refresh<W: WorkloadExt>(
+        &self,
+        api: &Api<W>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
         let deployments = api.list(&ListParams::default()).await?;
 
         for deployment in deployments.items.iter() {
-            self.update_cache(&deployment);
+            if deployment.did_successfully_deploy() {
+                self.update_cache(deployment);
+            }
+            self.is_new_generation(deployment);
         }
 
         Ok(())
     }
 
-    pub fn update_cache(&self, deployment: &Deployment) -> CacheOp {
+    /// Prime the cache with the deployments whose current rollout already failed, so failure
+    /// hooks don't fire again for them after a restart.
+    pub async fn refresh_failed<W: WorkloadExt>(
+        &self,
+        api: &Api<W>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let deployments = api.list(&ListParams::default()).await?;
+
+        for deployment in deployments.items.iter() {
+            if deployment.did_fail_to_deploy() {
+                self.update_cache(deployment);
+            }
+        }
+
+        Ok(())
+    }
+
+    #[cfg(feature = "metrics")]
+    pub fn len(&self) -> usize {
+        self.cache.lock().unwrap().len()
+    }
+
+    /// Whether the deployment's current pod template is the one we last saw finish rolling out.
+    pub fn is_unchanged(&self, deployment: &impl WorkloadExt) -> bool {
+        let cache = self.cache.lock().unwrap();
+
+        match deployment.pod_template_hash() {
+            Some(hash) => cache.get(&cache_key(deployment)) == Some(&hash),
+            None => false,
+        }
+    }
+
+    /// Record the generation of the deployment, returning whether its spec changed since the
+    /// last time it was recorded.
+    pub fn is_new_generation(&self, deployment: &impl Workload) -> bool {
+        let generation = deployment.meta().generation.unwrap_or_default();
+        let mut generations = self.generations.lock().unwrap();
+
+        generations.insert(cache_key(deployment), generation) != Some(generation)
+    }
+
+    pub fn update_cache(&self, deployment: &impl WorkloadExt) -> CacheOp {
         let mut cache = self.cache.lock().unwrap();
-        let key = (
-            deployment
-                .metadata
-                .namespace
-                .clone()
-                .expect("must have a namespace"),
-            deployment.metadata.name.clone().expect("must have a name"),
-        );
 
         if let Some(hash) = deployment.pod_template_hash() {
-            if let Some(old_hash) = cache.insert(key, hash.clone()) {
+            if let Some(old_hash) = cache.insert(cache_key(deployment), hash.clone()) {
                 if old_hash == hash {
                     return CacheOp::Unchanged;
                 }
             }
         }
-        return CacheOp::Changed;
+        CacheOp::Changed
     }
 }
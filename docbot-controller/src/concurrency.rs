@@ -0,0 +1,169 @@
+use crate::utils::JobExt;
+use crate::ResourceFormatter;
+use docbot_crd::{ConcurrencyPolicy, Hook, HOOK_NAME_LABEL};
+use k8s_openapi::api::batch::v1::Job;
+use kube::{
+    api::{DeleteParams, ListParams},
+    client::Client,
+    Api,
+};
+use std::time::Duration;
+use tracing::info;
+
+/// How long a replaced job gets to go away, pods included, before the run gives up.
+const REPLACE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// How often we check whether a replaced job is gone.
+const REPLACE_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// The jobs created by `hook` that have not finished yet. Jobs are matched on the owner
+/// reference `generate_from_template` sets, the label alone could be shared by hooks of the
+/// same name in other namespaces.
+fn active_jobs<'a, H: Hook>(hook: &H, jobs: &'a [Job]) -> Vec<&'a Job> {
+    jobs.iter()
+        .filter(|job| {
+            job.metadata
+                .owner_references
+                .iter()
+                .flatten()
+                .any(|owner| Some(&owner.uid) == hook.meta().uid.as_ref())
+        })
+        .filter(|job| !job.hook_phase().is_finished())
+        .collect()
+}
+
+/// Apply the concurrency policy of the hook to the jobs it still has running in `namespace`.
+/// Returns whether a new job should be created, a pre-rollout run that is skipped has to be
+/// released from the gate by the caller.
+pub async fn admit_job<H: Hook>(
+    client: &Client,
+    hook: &H,
+    namespace: &str,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let policy = hook.concurrency_policy();
+    if policy == ConcurrencyPolicy::Allow {
+        return Ok(true);
+    }
+
+    let job_api: Api<Job> = Api::namespaced(client.clone(), namespace);
+    let hook_name = hook.meta().name.clone().unwrap_or_default();
+    let jobs = job_api
+        .list(&ListParams::default().labels(&format!("{HOOK_NAME_LABEL}={hook_name}")))
+        .await?;
+
+    let active = active_jobs(hook, &jobs.items);
+    if active.is_empty() {
+        return Ok(true);
+    }
+
+    match policy {
+        ConcurrencyPolicy::Allow => Ok(true),
+        ConcurrencyPolicy::Forbid => {
+            info!(
+                "Skipping job for hook {}, {} earlier job(s) still running",
+                hook.meta().formatted_name(),
+                active.len()
+            );
+            Ok(false)
+        }
+        ConcurrencyPolicy::Replace => {
+            let mut replaced = Vec::new();
+            for job in active {
+                let job_name = job.metadata.name.clone().unwrap_or_default();
+                info!(
+                    "Replacing running job {} of hook {}",
+                    job_name,
+                    hook.meta().formatted_name()
+                );
+                // Delete the pods before the job, the replacement must not overlap them.
+                job_api
+                    .delete(&job_name, &DeleteParams::foreground())
+                    .await?;
+                replaced.push(job_name);
+            }
+            wait_until_deleted(&job_api, &replaced).await?;
+            Ok(true)
+        }
+    }
+}
+
+/// Wait for the jobs deleted in the foreground to be gone, which only happens once their pods
+/// are, for at most `REPLACE_TIMEOUT`.
+async fn wait_until_deleted(
+    job_api: &Api<Job>,
+    job_names: &[String],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let deadline = tokio::time::Instant::now() + REPLACE_TIMEOUT;
+    for job_name in job_names {
+        while job_api.get_opt(job_name).await?.is_some() {
+            if tokio::time::Instant::now() >= deadline {
+                return Err(format!(
+                    "replaced job {job_name} still exists after {REPLACE_TIMEOUT:?}"
+                )
+                .into());
+            }
+            tokio::time::sleep(REPLACE_POLL_INTERVAL).await;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use docbot_crd::DeploymentHook;
+
+    #[test]
+    fn only_unfinished_jobs_owned_by_the_hook_are_active() {
+        let hook: DeploymentHook = serde_yaml::from_str(
+            r#"
+apiVersion: apps.mx.com/v1
+kind: DeploymentHook
+metadata:
+  name: run-app-migrations
+  namespace: docbot-test
+  uid: "1234"
+spec:
+  concurrencyPolicy: Forbid
+  selector:
+    labels:
+      app: nginx
+  template:
+    name: nginx-template
+"#,
+        )
+        .unwrap();
+
+        let job = |name: &str, uid: &str, status: &str| -> Job {
+            serde_yaml::from_str(&format!(
+                r#"
+metadata:
+  name: {name}
+  ownerReferences:
+  - apiVersion: apps.mx.com/v1
+    kind: DeploymentHook
+    name: run-app-migrations
+    uid: "{uid}"
+status: {status}
+"#
+            ))
+            .unwrap()
+        };
+
+        let jobs = vec![
+            job("running", "1234", "{active: 1}"),
+            job(
+                "finished",
+                "1234",
+                "{conditions: [{type: Complete, status: 'True'}]}",
+            ),
+            job("other-hook", "5678", "{active: 1}"),
+        ];
+
+        let active: Vec<_> = active_jobs(&hook, &jobs)
+            .into_iter()
+            .map(|job| job.metadata.name.as_deref().unwrap())
+            .collect();
+        assert_eq!(active, vec!["running"]);
+    }
+}
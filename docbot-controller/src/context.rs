@@ -0,0 +1,30 @@
+use crate::cache::{
+    ClusterDeploymentHookCache, DeploymentHookCache, DeploymentPodTemplateHashCache,
+    NamespaceLabelCache,
+};
+use crate::events::Recorder;
+use crate::health::Health;
+use crate::leader::Leadership;
+use crate::retry::RetryQueue;
+use docbot_crd::{JobTemplateService, PodTemplateService};
+use kube::client::Client;
+
+/// Shared state handed to the watchers and the tasks they spawn. Every field is cheap to clone
+/// and clones share the same underlying caches.
+#[derive(Clone)]
+pub struct Context {
+    pub client: Client,
+    pub hook_cache: DeploymentHookCache,
+    pub cluster_hook_cache: ClusterDeploymentHookCache,
+    pub pod_template_service: PodTemplateService,
+    pub job_template_service: JobTemplateService,
+    /// Pod template hashes of the last successful rollout of every deployment.
+    pub deployment_cache: DeploymentPodTemplateHashCache,
+    /// Pod template hashes of the last failed rollout of every deployment.
+    pub failed_deployment_cache: DeploymentPodTemplateHashCache,
+    pub namespace_cache: NamespaceLabelCache,
+    pub recorder: Recorder,
+    pub retry_queue: RetryQueue,
+    pub health: Health,
+    pub leadership: Leadership,
+}
@@ -0,0 +1,96 @@
+use k8s_openapi::api::core::v1::{Event, EventSource, ObjectReference};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{ObjectMeta, Time};
+use k8s_openapi::chrono::Utc;
+use kube::{api::PostParams, client::Client, Api};
+use tracing::warn;
+
+/// Name reported as the source of every event docbot publishes.
+const REPORTING_COMPONENT: &str = "docbot";
+
+#[derive(Clone, Copy)]
+pub enum EventType {
+    Normal,
+    Warning,
+}
+
+/// Publishes `core/v1` events so they show up in `kubectl describe` of the object they are
+/// attached to. The `events.k8s.io/v1` api is not available in the api version we build
+/// against, so we can't use the recorder from `kube-runtime`.
+#[derive(Clone)]
+pub struct Recorder {
+    client: Client,
+}
+
+impl Recorder {
+    pub fn new(client: Client) -> Self {
+        Self { client }
+    }
+
+    /// Events are best effort, failing to publish one is logged rather than returned.
+    pub async fn publish(
+        &self,
+        reference: ObjectReference,
+        type_: EventType,
+        reason: &str,
+        message: String,
+    ) {
+        // Cluster scoped objects publish their events in the default namespace.
+        let namespace = reference
+            .namespace
+            .clone()
+            .unwrap_or_else(|| "default".to_string());
+        let now = Time(Utc::now());
+
+        let event = Event {
+            metadata: ObjectMeta {
+                generate_name: Some(format!("{REPORTING_COMPONENT}-")),
+                namespace: Some(namespace.clone()),
+                ..ObjectMeta::default()
+            },
+            involved_object: reference.clone(),
+            type_: Some(
+                match type_ {
+                    EventType::Normal => "Normal",
+                    EventType::Warning => "Warning",
+                }
+                .to_string(),
+            ),
+            reason: Some(reason.to_string()),
+            message: Some(message),
+            count: Some(1),
+            first_timestamp: Some(now.clone()),
+            last_timestamp: Some(now),
+            source: Some(EventSource {
+                component: Some(REPORTING_COMPONENT.to_string()),
+                ..EventSource::default()
+            }),
+            reporting_component: Some(REPORTING_COMPONENT.to_string()),
+            ..Event::default()
+        };
+
+        let events_api: Api<Event> = Api::namespaced(self.client.clone(), &namespace);
+        if let Err(err) = events_api.create(&PostParams::default(), &event).await {
+            warn!(
+                "Failed to publish {} event for {:?} {:?}, error: {:?}",
+                reason, reference.kind, reference.name, err
+            );
+        }
+    }
+
+    /// Publish the event on a hook and on the deployment it ran for, so the event shows up for
+    /// whoever owns either of them.
+    pub async fn publish_for_hook(
+        &self,
+        hook: ObjectReference,
+        deployment: Option<ObjectReference>,
+        type_: EventType,
+        reason: &str,
+        message: String,
+    ) {
+        if let Some(deployment) = deployment {
+            self.publish(deployment, type_, reason, message.clone())
+                .await;
+        }
+        self.publish(hook, type_, reason, message).await;
+    }
+}
@@ -0,0 +1,476 @@
+use crate::cache::DeploymentPodTemplateHashCache;
+use crate::events::{EventType, Recorder};
+use crate::status::{hook_owner, update_hook_status, update_owner_status};
+use crate::utils::{JobExt, WorkloadExt};
+use crate::ResourceFormatter;
+use docbot_crd::{Hook, HookCondition, HookPhase, CONDITION_ROLLOUT_BLOCKED};
+use k8s_openapi::api::apps::v1::Deployment;
+use k8s_openapi::api::batch::v1::Job;
+use kube::{
+    api::{Patch, PatchParams},
+    client::Client,
+    Api, Resource,
+};
+use serde_json::json;
+use tracing::info;
+
+/// Set on a deployment held by the pre-rollout gate, the pod template hash it was paused for.
+const PRE_ROLLOUT_HASH_ANNOTATION: &str = "apps.mx.com/pre-rollout-hash";
+
+/// Set on a deployment held by the pre-rollout gate, a comma separated list of the
+/// `namespace/name` of every hook whose job has not succeeded yet.
+const PRE_ROLLOUT_PENDING_ANNOTATION: &str = "apps.mx.com/pre-rollout-pending";
+
+/// Set on a deployment when one of its pre-rollout hooks failed, the `namespace/name` of the
+/// hook that failed.
+const PRE_ROLLOUT_FAILED_ANNOTATION: &str = "apps.mx.com/pre-rollout-failed";
+
+/// Set on pre-rollout jobs, the `namespace/name` of the deployment the job is holding.
+const PRE_ROLLOUT_DEPLOYMENT_ANNOTATION: &str = "apps.mx.com/pre-rollout-deployment";
+
+fn annotation<'a>(resource: &'a impl Resource, key: &str) -> Option<&'a str> {
+    resource
+        .meta()
+        .annotations
+        .as_ref()
+        .and_then(|annotations| annotations.get(key))
+        .map(|value| value.as_str())
+}
+
+/// Whether the deployment can be gated and carries a pod template that has neither finished
+/// rolling out before nor been gated already, meaning pre-rollout hooks should run for it.
+pub fn needs_gate(
+    deployment: &impl WorkloadExt,
+    deployment_cache: &DeploymentPodTemplateHashCache,
+) -> bool {
+    if !deployment.supports_pre_rollout_gate() {
+        return false;
+    }
+
+    match deployment.pod_template_hash() {
+        Some(hash) => {
+            annotation(deployment, PRE_ROLLOUT_HASH_ANNOTATION) != Some(hash.as_str())
+                && !deployment_cache.is_unchanged(deployment)
+        }
+        None => false,
+    }
+}
+
+/// Pause the deployment so the new pod template doesn't roll out until every hook in `pending`
+/// succeeded.
+pub async fn pause_deployment(
+    client: &Client,
+    recorder: &Recorder,
+    deployment: &impl WorkloadExt,
+    pending: &[String],
+) -> Result<(), Box<dyn std::error::Error>> {
+    info!(
+        "Pausing deployment {} until pre-rollout hooks {:?} succeed",
+        deployment.meta().formatted_name(),
+        pending
+    );
+
+    patch_deployment(
+        client,
+        deployment,
+        json!({
+            "metadata": {
+                "annotations": {
+                    PRE_ROLLOUT_HASH_ANNOTATION: deployment.pod_template_hash(),
+                    PRE_ROLLOUT_PENDING_ANNOTATION: pending.join(","),
+                    PRE_ROLLOUT_FAILED_ANNOTATION: null,
+                }
+            },
+            "spec": { "paused": true }
+        }),
+    )
+    .await?;
+
+    recorder
+        .publish(
+            deployment.object_reference(),
+            EventType::Normal,
+            "PausedForPreRolloutHooks",
+            format!(
+                "Paused until pre-rollout hooks {} succeed",
+                pending.join(", ")
+            ),
+        )
+        .await;
+
+    Ok(())
+}
+
+/// Report on the hook that it is holding the deployment. The jobs still have to be created, so
+/// a failed status update is only logged.
+pub async fn record_blocked<H: Hook>(client: &Client, hook: &H, deployment: &impl WorkloadExt) {
+    let result = update_hook_status::<H, _>(
+        client,
+        hook.meta().namespace.as_deref(),
+        hook.meta().name.as_deref().unwrap_or_default(),
+        |status| {
+            status.set_condition(HookCondition::new(
+                CONDITION_ROLLOUT_BLOCKED,
+                true,
+                "PreRolloutHookRunning",
+                format!(
+                    "Deployment {} is paused until the hook succeeds",
+                    deployment.meta().formatted_name()
+                ),
+            ))
+        },
+    )
+    .await;
+
+    if let Err(err) = result {
+        info!(
+            "Failed to update the status of {} {}, error: {:?}",
+            H::kind(&()),
+            hook.meta().formatted_name(),
+            err
+        );
+    }
+}
+
+/// Mark a generated job as holding the deployment, so its outcome can be tied back to the gate.
+pub fn annotate_job(job: &mut Job, deployment: &impl WorkloadExt) {
+    let annotations = job
+        .metadata
+        .annotations
+        .get_or_insert_with(Default::default);
+    annotations.insert(
+        PRE_ROLLOUT_DEPLOYMENT_ANNOTATION.to_string(),
+        deployment.meta().formatted_name(),
+    );
+    if let Some(hash) = deployment.pod_template_hash() {
+        annotations.insert(PRE_ROLLOUT_HASH_ANNOTATION.to_string(), hash);
+    }
+}
+
+/// Resume the deployment held by a pre-rollout job once every pending hook succeeded, or
+/// leave it paused and report the failure when the job failed.
+pub async fn record_job_progress(
+    client: &Client,
+    recorder: &Recorder,
+    job: &Job,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (deployment_name, hash) = match (
+        annotation(job, PRE_ROLLOUT_DEPLOYMENT_ANNOTATION),
+        annotation(job, PRE_ROLLOUT_HASH_ANNOTATION),
+    ) {
+        (Some(deployment_name), Some(hash)) => (deployment_name, hash),
+        _ => return Ok(()),
+    };
+
+    let phase = job.hook_phase();
+    if !phase.is_finished() {
+        return Ok(());
+    }
+
+    // Cluster hooks are recorded in the pending annotation by their name alone.
+    let hook_name = match hook_owner(job) {
+        Some(owner) if owner.kind == "ClusterDeploymentHook" => owner.name.clone(),
+        Some(owner) => format!(
+            "{}/{}",
+            job.metadata.namespace.as_deref().unwrap_or_default(),
+            owner.name
+        ),
+        None => return Ok(()),
+    };
+
+    let (namespace, name) = match deployment_name.split_once('/') {
+        Some(pair) => pair,
+        None => return Ok(()),
+    };
+    let deployment_api: Api<Deployment> = Api::namespaced(client.clone(), namespace);
+    let deployment = deployment_api.get(name).await?;
+
+    // The deployment moved on to another pod template since this job was created.
+    if annotation(&deployment, PRE_ROLLOUT_HASH_ANNOTATION) != Some(hash) {
+        return Ok(());
+    }
+
+    if !pending_hooks(&deployment).contains(&hook_name.as_str())
+        || annotation(&deployment, PRE_ROLLOUT_FAILED_ANNOTATION) == Some(hook_name.as_str())
+    {
+        return Ok(());
+    }
+
+    if phase == HookPhase::Succeeded {
+        if release(client, &deployment, &hook_name).await? {
+            recorder
+                .publish(
+                    deployment.object_ref(&()),
+                    EventType::Normal,
+                    "PreRolloutHooksSucceeded",
+                    "All pre-rollout hooks succeeded, resuming the rollout".to_string(),
+                )
+                .await;
+        }
+
+        update_owner_status(client, job, |status| {
+            status.set_condition(HookCondition::new(
+                CONDITION_ROLLOUT_BLOCKED,
+                false,
+                "PreRolloutHookSucceeded",
+                format!("Deployment {deployment_name} is no longer held by this hook"),
+            ))
+        })
+        .await?;
+    } else {
+        let message = format!(
+            "Pre-rollout hook {} failed, deployment {} is left paused",
+            hook_name, deployment_name
+        );
+        info!("{}", message);
+
+        patch_deployment(
+            client,
+            &deployment,
+            json!({
+                "metadata": { "annotations": { PRE_ROLLOUT_FAILED_ANNOTATION: hook_name } }
+            }),
+        )
+        .await?;
+
+        update_owner_status(client, job, |status| {
+            status.set_condition(HookCondition::new(
+                CONDITION_ROLLOUT_BLOCKED,
+                true,
+                "PreRolloutHookFailed",
+                message.clone(),
+            ))
+        })
+        .await?;
+
+        recorder
+            .publish(
+                deployment.object_ref(&()),
+                EventType::Warning,
+                "PreRolloutHookFailed",
+                message,
+            )
+            .await;
+    }
+
+    Ok(())
+}
+
+/// The hooks the deployment is still held for.
+fn pending_hooks(deployment: &Deployment) -> Vec<&str> {
+    annotation(deployment, PRE_ROLLOUT_PENDING_ANNOTATION)
+        .unwrap_or_default()
+        .split(',')
+        .filter(|pending| !pending.is_empty())
+        .collect()
+}
+
+/// Stop holding the deployment for `hook_name`, resuming it once no other hook is pending.
+/// Returns whether the deployment was resumed.
+async fn release(
+    client: &Client,
+    deployment: &Deployment,
+    hook_name: &str,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let mut pending = pending_hooks(deployment);
+    pending.retain(|pending| *pending != hook_name);
+
+    if !pending.is_empty() {
+        patch_deployment(
+            client,
+            deployment,
+            json!({
+                "metadata": { "annotations": { PRE_ROLLOUT_PENDING_ANNOTATION: pending.join(",") } }
+            }),
+        )
+        .await?;
+        return Ok(false);
+    }
+
+    info!(
+        "No pre-rollout hooks pending anymore, resuming deployment {}",
+        deployment.metadata.formatted_name()
+    );
+    patch_deployment(
+        client,
+        deployment,
+        json!({
+            "metadata": { "annotations": { PRE_ROLLOUT_PENDING_ANNOTATION: null } },
+            "spec": { "paused": false }
+        }),
+    )
+    .await?;
+
+    Ok(true)
+}
+
+/// Stop holding the deployment for a pre-rollout hook whose run was skipped, so the deployment
+/// doesn't stay paused for a job that is never created.
+pub async fn release_skipped<H: Hook>(
+    client: &Client,
+    recorder: &Recorder,
+    hook: &H,
+    workload: &impl WorkloadExt,
+    reason: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let deployment_api: Api<Deployment> = Api::namespaced(
+        client.clone(),
+        workload.meta().namespace.as_deref().unwrap_or("default"),
+    );
+    let deployment = deployment_api
+        .get(workload.meta().name.as_deref().unwrap_or_default())
+        .await?;
+
+    // The deployment isn't held for this pod template, or not for this hook.
+    let hook_name = hook.meta().formatted_name();
+    if annotation(&deployment, PRE_ROLLOUT_HASH_ANNOTATION)
+        != workload.pod_template_hash().as_deref()
+        || !pending_hooks(&deployment).contains(&hook_name.as_str())
+    {
+        return Ok(());
+    }
+
+    let resumed = release(client, &deployment, &hook_name).await?;
+    let message = format!(
+        "Skipped pre-rollout hook {hook_name} because {reason}, {}",
+        if resumed {
+            "resuming the rollout"
+        } else {
+            "no longer waiting for it"
+        }
+    );
+    info!(
+        "{} for deployment {}",
+        message,
+        deployment.metadata.formatted_name()
+    );
+
+    update_hook_status::<H, _>(
+        client,
+        hook.meta().namespace.as_deref(),
+        hook.meta().name.as_deref().unwrap_or_default(),
+        |status| {
+            status.set_condition(HookCondition::new(
+                CONDITION_ROLLOUT_BLOCKED,
+                false,
+                "PreRolloutHookSkipped",
+                message.clone(),
+            ))
+        },
+    )
+    .await?;
+
+    recorder
+        .publish_for_hook(
+            hook.object_ref(&()),
+            Some(deployment.object_reference()),
+            EventType::Warning,
+            "PreRolloutHookSkipped",
+            message,
+        )
+        .await;
+
+    Ok(())
+}
+
+async fn patch_deployment(
+    client: &Client,
+    deployment: &impl Resource,
+    patch: serde_json::Value,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let deployment_api: Api<Deployment> = Api::namespaced(
+        client.clone(),
+        deployment.meta().namespace.as_deref().unwrap_or("default"),
+    );
+
+    deployment_api
+        .patch(
+            deployment.meta().name.as_deref().unwrap_or_default(),
+            &PatchParams::default(),
+            &Patch::Merge(patch),
+        )
+        .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use k8s_openapi::api::apps::v1::StatefulSet;
+
+    fn example_deployment() -> Deployment {
+        let contents = r#"
+---
+apiVersion: apps/v1
+kind: Deployment
+metadata:
+  name: nginx-deployment
+  namespace: docbot-test
+spec:
+  selector:
+    matchLabels:
+      app: nginx
+  template:
+    spec:
+      containers:
+      - name: nginx
+        image: nginx:1.14.2
+"#;
+
+        serde_yaml::from_str(contents).unwrap()
+    }
+
+    #[test]
+    fn gating_new_pod_templates_only_once() {
+        let deployment_cache = DeploymentPodTemplateHashCache::default();
+        let mut deployment = example_deployment();
+
+        // A pod template we have never seen roll out needs to be gated.
+        assert!(needs_gate(&deployment, &deployment_cache));
+
+        // Once the gate is opened for the pod template it shouldn't be opened again.
+        deployment.metadata.annotations = Some(
+            [(
+                PRE_ROLLOUT_HASH_ANNOTATION.to_string(),
+                deployment.pod_template_hash().unwrap(),
+            )]
+            .into(),
+        );
+        assert!(!needs_gate(&deployment, &deployment_cache));
+
+        // Pod templates that already rolled out, e.g. on startup, are never gated.
+        deployment.metadata.annotations = None;
+        deployment_cache.update_cache(&deployment);
+        assert!(!needs_gate(&deployment, &deployment_cache));
+    }
+
+    #[test]
+    fn only_workloads_that_can_be_paused_are_gated() {
+        let statefulset: StatefulSet = serde_yaml::from_str(
+            r#"
+apiVersion: apps/v1
+kind: StatefulSet
+metadata:
+  name: postgres
+  namespace: docbot-test
+spec:
+  serviceName: postgres
+  selector:
+    matchLabels:
+      app: postgres
+  template:
+    spec:
+      containers:
+      - name: postgres
+        image: postgres:14
+"#,
+        )
+        .unwrap();
+
+        assert!(!needs_gate(
+            &statefulset,
+            &DeploymentPodTemplateHashCache::default()
+        ));
+    }
+}
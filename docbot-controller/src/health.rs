@@ -0,0 +1,143 @@
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, Server, StatusCode,
+};
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Readiness and liveness of the controller. Clones share the same state.
+#[derive(Debug, Clone)]
+pub struct Health {
+    ready: Arc<AtomicBool>,
+    /// Since when every watcher has been failing, unset while it works.
+    watchers: Arc<Mutex<BTreeMap<String, Option<Instant>>>>,
+    /// How long a watcher may keep failing before the controller is considered stuck.
+    window: Duration,
+}
+
+impl Health {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            ready: Arc::new(AtomicBool::new(false)),
+            watchers: Arc::new(Mutex::new(BTreeMap::new())),
+            window,
+        }
+    }
+
+    /// Mark the caches as primed.
+    pub fn set_ready(&self) {
+        self.ready.store(true, Ordering::SeqCst);
+    }
+
+    /// Start keeping track of a watcher, giving it a full window to list for the first time.
+    pub fn watching(&self, watcher: &str) {
+        self.watchers
+            .lock()
+            .unwrap()
+            .entry(watcher.to_string())
+            .or_insert_with(|| Some(Instant::now()));
+    }
+
+    /// Record that the watcher received an event or listed successfully.
+    pub fn observed(&self, watcher: &str) {
+        self.watchers
+            .lock()
+            .unwrap()
+            .insert(watcher.to_string(), None);
+    }
+
+    /// Record that the watcher failed, it keeps failing until it receives an event or lists
+    /// successfully again.
+    pub fn failed(&self, watcher: &str) {
+        self.watchers
+            .lock()
+            .unwrap()
+            .entry(watcher.to_string())
+            .or_default()
+            .get_or_insert_with(Instant::now);
+    }
+
+    /// The watchers that have been failing for longer than the window, or never listed
+    /// successfully within it. A quiet watcher that works is never stale.
+    fn stale_watchers(&self, now: Instant) -> Vec<String> {
+        self.watchers
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, failing_since)| {
+                failing_since
+                    .is_some_and(|since| now.saturating_duration_since(since) > self.window)
+            })
+            .map(|(watcher, _)| watcher.clone())
+            .collect()
+    }
+
+    fn respond(&self, request: &Request<Body>) -> Response<Body> {
+        let (status, body) = match request.uri().path() {
+            "/healthz" => match self.stale_watchers(Instant::now()) {
+                stale if stale.is_empty() => (StatusCode::OK, "ok".to_string()),
+                stale => (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    format!("watchers stale: {}", stale.join(", ")),
+                ),
+            },
+            "/readyz" if self.ready.load(Ordering::SeqCst) => (StatusCode::OK, "ok".to_string()),
+            "/readyz" => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "caches are not primed yet".to_string(),
+            ),
+            _ => (StatusCode::NOT_FOUND, String::new()),
+        };
+
+        Response::builder()
+            .status(status)
+            .body(Body::from(body))
+            .unwrap()
+    }
+}
+
+/// Serve `/healthz` and `/readyz` on `addr` until the server fails.
+pub async fn serve(health: Health, addr: SocketAddr) -> Result<(), hyper::Error> {
+    let make_service = make_service_fn(move |_| {
+        let health = health.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let response = health.respond(&request);
+                async move { Ok::<_, Infallible>(response) }
+            }))
+        }
+    });
+
+    Server::bind(&addr).serve(make_service).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn watchers_failing_for_the_window_are_stale() {
+        let health = Health::new(Duration::from_secs(60));
+        health.watching("Deployment");
+        health.observed("Deployment");
+        health.watching("DeploymentHook");
+        health.watching("Job");
+        health.observed("Job");
+        health.failed("Job");
+        health.watching("Namespace");
+        health.observed("Namespace");
+        health.failed("Namespace");
+        health.observed("Namespace");
+
+        let now = Instant::now();
+        assert!(health.stale_watchers(now).is_empty());
+
+        // Quiet watchers stay healthy, the ones that never listed or keep failing go stale.
+        let later = now + Duration::from_secs(61);
+        assert_eq!(health.stale_watchers(later), vec!["DeploymentHook", "Job"]);
+    }
+}
@@ -0,0 +1,183 @@
+use crate::context::Context;
+use crate::utils::JobExt;
+use crate::ResourceFormatter;
+use docbot_crd::{Hook, HookPhase, HOOK_NAME_LABEL};
+use k8s_openapi::api::batch::v1::Job;
+use kube::{
+    api::{DeleteParams, ListParams},
+    Api,
+};
+use tracing::info;
+
+/// The finished jobs of the hook beyond its history limits, oldest first.
+fn jobs_to_prune<'a, H: Hook>(hook: &H, jobs: &'a [Job]) -> Vec<&'a Job> {
+    let mut owned: Vec<&Job> = jobs
+        .iter()
+        .filter(|job| {
+            job.metadata
+                .owner_references
+                .iter()
+                .flatten()
+                .any(|owner| Some(&owner.uid) == hook.meta().uid.as_ref())
+        })
+        .collect();
+    owned.sort_by_key(|job| job.metadata.creation_timestamp.clone());
+
+    let mut pruned = Vec::new();
+    for (phase, limit) in [
+        (HookPhase::Succeeded, hook.successful_jobs_history_limit()),
+        (HookPhase::Failed, hook.failed_jobs_history_limit()),
+    ] {
+        let limit = match limit {
+            Some(limit) => limit as usize,
+            None => continue,
+        };
+
+        let finished: Vec<&Job> = owned
+            .iter()
+            .copied()
+            .filter(|job| job.hook_phase() == phase)
+            .collect();
+        let excess = finished.len().saturating_sub(limit);
+        pruned.extend(finished.into_iter().take(excess));
+    }
+
+    pruned.sort_by_key(|job| job.metadata.creation_timestamp.clone());
+    pruned
+}
+
+/// Delete the oldest finished jobs of the hook owning `job` once the job finishes and takes
+/// the hook over one of its history limits.
+pub async fn record_job_progress(
+    ctx: &Context,
+    job: &Job,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if !job.hook_phase().is_finished() {
+        return Ok(());
+    }
+
+    let owner = job
+        .metadata
+        .owner_references
+        .iter()
+        .flatten()
+        .find(|owner| owner.kind == "DeploymentHook" || owner.kind == "ClusterDeploymentHook");
+
+    match owner {
+        Some(owner) if owner.kind == "DeploymentHook" => {
+            let namespace = job.metadata.namespace.as_deref().unwrap_or_default();
+            match ctx.hook_cache.get(namespace, &owner.name) {
+                Some(hook) => prune_jobs(ctx, &hook).await,
+                None => Ok(()),
+            }
+        }
+        Some(owner) => match ctx.cluster_hook_cache.get("", &owner.name) {
+            Some(hook) => prune_jobs(ctx, &hook).await,
+            None => Ok(()),
+        },
+        None => Ok(()),
+    }
+}
+
+async fn prune_jobs<H: Hook>(ctx: &Context, hook: &H) -> Result<(), Box<dyn std::error::Error>> {
+    if hook.successful_jobs_history_limit().is_none() && hook.failed_jobs_history_limit().is_none()
+    {
+        return Ok(());
+    }
+
+    // Jobs of cluster hooks are spread over the namespaces of the deployments they ran for.
+    let job_api: Api<Job> = match hook.meta().namespace.as_deref() {
+        Some(namespace) => Api::namespaced(ctx.client.clone(), namespace),
+        None => Api::all(ctx.client.clone()),
+    };
+    let hook_name = hook.meta().name.clone().unwrap_or_default();
+    let jobs = job_api
+        .list(&ListParams::default().labels(&format!("{HOOK_NAME_LABEL}={hook_name}")))
+        .await?;
+
+    for job in jobs_to_prune(hook, &jobs.items) {
+        info!(
+            "Deleting job {} of {} {}, it is beyond the job history limit",
+            job.metadata.formatted_name(),
+            H::kind(&()),
+            hook.meta().formatted_name()
+        );
+
+        let job_api: Api<Job> = Api::namespaced(
+            ctx.client.clone(),
+            job.metadata.namespace.as_deref().unwrap_or_default(),
+        );
+        job_api
+            .delete(
+                job.metadata.name.as_deref().unwrap_or_default(),
+                &DeleteParams::background(),
+            )
+            .await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use docbot_crd::DeploymentHook;
+
+    #[test]
+    fn prunes_the_oldest_jobs_beyond_each_limit() {
+        let hook: DeploymentHook = serde_yaml::from_str(
+            r#"
+apiVersion: apps.mx.com/v1
+kind: DeploymentHook
+metadata:
+  name: run-app-migrations
+  namespace: docbot-test
+  uid: "1234"
+spec:
+  successfulJobsHistoryLimit: 1
+  failedJobsHistoryLimit: 2
+  selector:
+    labels:
+      app: nginx
+  template:
+    name: nginx-template
+"#,
+        )
+        .unwrap();
+
+        let job = |name: &str, uid: &str, created: &str, condition: &str| -> Job {
+            serde_yaml::from_str(&format!(
+                r#"
+metadata:
+  name: {name}
+  creationTimestamp: "2023-01-0{created}T00:00:00Z"
+  ownerReferences:
+  - apiVersion: apps.mx.com/v1
+    kind: DeploymentHook
+    name: run-app-migrations
+    uid: "{uid}"
+status:
+  conditions: [{{type: {condition}, status: 'True'}}]
+"#
+            ))
+            .unwrap()
+        };
+
+        let jobs = vec![
+            job("succeeded-3", "1234", "7", "Complete"),
+            job("succeeded-1", "1234", "1", "Complete"),
+            job("succeeded-2", "1234", "4", "Complete"),
+            job("failed-1", "1234", "2", "Failed"),
+            job("failed-2", "1234", "3", "Failed"),
+            job("failed-3", "1234", "5", "Failed"),
+            job("running", "1234", "6", "Suspended"),
+            job("other-hook", "5678", "1", "Complete"),
+        ];
+
+        let pruned: Vec<_> = jobs_to_prune(&hook, &jobs)
+            .into_iter()
+            .map(|job| job.metadata.name.as_deref().unwrap())
+            .collect();
+        assert_eq!(pruned, vec!["succeeded-1", "failed-1", "succeeded-2"]);
+    }
+}
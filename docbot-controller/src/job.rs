@@ -1,34 +1,270 @@
-use docbot_crd::DeploymentHook;
+use crate::utils::WorkloadExt;
+use docbot_crd::{DeploymentImage, Hook, TargetKind, HOOK_NAME_LABEL};
 use k8s_openapi::api::batch::v1::{Job, JobSpec};
-use k8s_openapi::api::core::v1::PodTemplate;
+use k8s_openapi::api::batch::v1beta1::JobTemplateSpec;
+use k8s_openapi::api::core::v1::{Container, EnvVar, ObjectReference, PodTemplate};
 use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
-use kube::Resource;
+use std::collections::BTreeMap;
 
-pub fn generate_from_template(
-    hook: &DeploymentHook,
+/// Annotations describing the deployment that triggered a job.
+const DEPLOYMENT_KIND_ANNOTATION: &str = "apps.mx.com/deployment-kind";
+const DEPLOYMENT_NAME_ANNOTATION: &str = "apps.mx.com/deployment-name";
+const DEPLOYMENT_NAMESPACE_ANNOTATION: &str = "apps.mx.com/deployment-namespace";
+const DEPLOYMENT_REVISION_JOB_ANNOTATION: &str = "apps.mx.com/deployment-revision";
+const POD_TEMPLATE_HASH_ANNOTATION: &str = "apps.mx.com/pod-template-hash";
+/// Only an annotation, the uid is of no use to the job itself.
+const DEPLOYMENT_UID_ANNOTATION: &str = "apps.mx.com/deployment-uid";
+/// Prefix of the per container image annotations, the container name is appended.
+const DEPLOYMENT_IMAGE_ANNOTATION_PREFIX: &str = "images.apps.mx.com/";
+
+/// Environment variable name carrying the image of a deployment container, e.g. the image of
+/// the `web-app` container is exposed as `DOCBOT_IMAGE_WEB_APP`.
+fn image_env_var_name(container_name: &str) -> String {
+    let name: String = container_name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+
+    format!("DOCBOT_IMAGE_{name}")
+}
+
+/// Everything the job gets to know about the deployment that triggered it, as
+/// `(env var, annotation, value)` triples.
+fn deployment_context(deployment: &impl WorkloadExt) -> Vec<(String, String, String)> {
+    let mut context = vec![
+        (
+            "DOCBOT_DEPLOYMENT_KIND".to_string(),
+            DEPLOYMENT_KIND_ANNOTATION.to_string(),
+            deployment.target_kind().kind().to_string(),
+        ),
+        (
+            "DOCBOT_DEPLOYMENT_NAME".to_string(),
+            DEPLOYMENT_NAME_ANNOTATION.to_string(),
+            deployment.meta().name.clone().unwrap_or_default(),
+        ),
+        (
+            "DOCBOT_DEPLOYMENT_NAMESPACE".to_string(),
+            DEPLOYMENT_NAMESPACE_ANNOTATION.to_string(),
+            deployment.meta().namespace.clone().unwrap_or_default(),
+        ),
+        (
+            "DOCBOT_DEPLOYMENT_REVISION".to_string(),
+            DEPLOYMENT_REVISION_JOB_ANNOTATION.to_string(),
+            deployment.revision().unwrap_or_default(),
+        ),
+        (
+            "DOCBOT_POD_TEMPLATE_HASH".to_string(),
+            POD_TEMPLATE_HASH_ANNOTATION.to_string(),
+            deployment.pod_template_hash().unwrap_or_default(),
+        ),
+    ];
+
+    let containers = deployment
+        .pod_template()
+        .and_then(|template| template.spec)
+        .map(|pod_spec| pod_spec.containers)
+        .unwrap_or_default();
+
+    for container in containers {
+        context.push((
+            image_env_var_name(&container.name),
+            format!("{DEPLOYMENT_IMAGE_ANNOTATION_PREFIX}{}", container.name),
+            container.image.clone().unwrap_or_default(),
+        ));
+    }
+
+    context
+}
+
+/// The deployment that triggered a job, read back from the annotations
+/// `inject_deployment_context` sets.
+pub struct TriggeringDeployment {
+    pub kind: TargetKind,
+    pub namespace: String,
+    pub name: String,
+    pub pod_template_hash: String,
+    pub uid: Option<String>,
+}
+
+impl TriggeringDeployment {
+    pub fn object_reference(&self) -> ObjectReference {
+        ObjectReference {
+            api_version: Some(self.kind.api_version().to_string()),
+            kind: Some(self.kind.kind().to_string()),
+            name: Some(self.name.clone()),
+            namespace: Some(self.namespace.clone()),
+            uid: self.uid.clone(),
+            ..ObjectReference::default()
+        }
+    }
+}
+
+pub fn triggering_deployment(job: &Job) -> Option<TriggeringDeployment> {
+    let annotations = job.metadata.annotations.as_ref()?;
+
+    Some(TriggeringDeployment {
+        // Jobs created before other kinds of workload were supported don't record the kind.
+        kind: match annotations.get(DEPLOYMENT_KIND_ANNOTATION) {
+            Some(kind) => TargetKind::from_kind(kind)?,
+            None => TargetKind::Deployment,
+        },
+        namespace: annotations.get(DEPLOYMENT_NAMESPACE_ANNOTATION)?.clone(),
+        name: annotations.get(DEPLOYMENT_NAME_ANNOTATION)?.clone(),
+        pod_template_hash: annotations.get(POD_TEMPLATE_HASH_ANNOTATION)?.clone(),
+        uid: annotations.get(DEPLOYMENT_UID_ANNOTATION).cloned(),
+    })
+}
+
+fn inject_env(container: &mut Container, name: &str, value: &str) {
+    let env = container.env.get_or_insert_with(Vec::new);
+    env.retain(|env_var| env_var.name != name);
+    env.push(EnvVar {
+        name: name.to_string(),
+        value: Some(value.to_string()),
+        ..EnvVar::default()
+    });
+}
+
+/// Expose the triggering deployment to the job, as environment variables in every container
+/// and as annotations on the job itself.
+fn inject_deployment_context(job: &mut Job, deployment: &impl WorkloadExt) {
+    let context = deployment_context(deployment);
+
+    let annotations = job.metadata.annotations.get_or_insert_with(BTreeMap::new);
+    for (_, annotation, value) in &context {
+        annotations.insert(annotation.clone(), value.clone());
+    }
+    if let Some(ref uid) = deployment.meta().uid {
+        annotations.insert(DEPLOYMENT_UID_ANNOTATION.to_string(), uid.clone());
+    }
+
+    let pod_spec = job
+        .spec
+        .as_mut()
+        .and_then(|job_spec| job_spec.template.spec.as_mut());
+
+    if let Some(pod_spec) = pod_spec {
+        let init_containers = pod_spec.init_containers.iter_mut().flatten();
+        for container in pod_spec.containers.iter_mut().chain(init_containers) {
+            for (env_var, _, value) in &context {
+                inject_env(container, env_var, value);
+            }
+        }
+    }
+}
+
+/// Swap the image of every container listed in `useDeploymentImage` for the image the
+/// deployment just rolled out.
+fn use_deployment_images(
+    job: &mut Job,
+    images: &[DeploymentImage],
+    deployment: &impl WorkloadExt,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let deployment_containers = deployment
+        .pod_template()
+        .and_then(|template| template.spec)
+        .map(|pod_spec| pod_spec.containers)
+        .unwrap_or_default();
+
+    let mut pod_spec = job
+        .spec
+        .as_mut()
+        .and_then(|job_spec| job_spec.template.spec.as_mut());
+
+    for image in images {
+        let deployment_container = image.deployment_container();
+        let deployment_image = deployment_containers
+            .iter()
+            .find(|container| container.name == deployment_container)
+            .and_then(|container| container.image.clone())
+            .ok_or_else(|| {
+                format!("Deployment has no container {deployment_container} to take the image from")
+            })?;
+
+        let container = pod_spec.as_deref_mut().and_then(|pod_spec| {
+            let init_containers = pod_spec.init_containers.iter_mut().flatten();
+            pod_spec
+                .containers
+                .iter_mut()
+                .chain(init_containers)
+                .find(|container| container.name == image.container)
+        });
+
+        match container {
+            Some(container) => container.image = Some(deployment_image),
+            None => {
+                return Err(format!(
+                    "Pod template has no container {} to set the image of",
+                    image.container
+                )
+                .into())
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The pod template of a referenced job template, in the namespace the job is created in.
+pub fn pod_template_from_job_template(
+    job_template: &JobTemplateSpec,
+    namespace: &str,
+) -> PodTemplate {
+    PodTemplate {
+        metadata: ObjectMeta {
+            namespace: Some(namespace.to_string()),
+            labels: job_template
+                .metadata
+                .as_ref()
+                .and_then(|metadata| metadata.labels.clone()),
+            ..ObjectMeta::default()
+        },
+        template: job_template.spec.as_ref().map(|spec| spec.template.clone()),
+    }
+}
+
+pub fn generate_from_template<H: Hook>(
+    hook: &H,
     template: PodTemplate,
+    job_template: Option<JobTemplateSpec>,
+    deployment: &impl WorkloadExt,
 ) -> Result<Job, Box<dyn std::error::Error>> {
     let mut job = Job::default();
-    job.metadata = ObjectMeta::default();
     if let Some(ref mut annotations) = job.metadata.annotations {
         annotations.remove("kubectl.kubernetes.io/last-applied-configuration");
     }
-    job.metadata.labels = template.metadata.labels.clone();
+    let hook_name = hook.meta().name.clone().expect("name is missing");
+
+    // Label the job with the hook name so the controller can find the jobs it created.
+    let mut labels = template.metadata.labels.clone().unwrap_or_default();
+    labels.insert(HOOK_NAME_LABEL.to_string(), hook_name.clone());
+    job.metadata.labels = Some(labels);
     job.metadata.namespace = template.metadata.namespace.clone();
-    job.metadata.generate_name = Some(format!(
-        "docbot-hook-{}-",
-        &hook.metadata.name.as_ref().expect("name is missing")
-    ));
+    job.metadata.generate_name = Some(format!("docbot-hook-{hook_name}-"));
 
     // Set owner reference so job is a child of the hook resource that spawned it
     if let Some(owner_ref) = hook.controller_owner_ref(&()) {
         job.metadata.owner_references = Some(vec![owner_ref]);
     }
 
-    let mut job_spec = JobSpec::default();
-
-    // Set the job ttl after it finishes.
-    job_spec.ttl_seconds_after_finished = hook.spec.template.ttl_seconds_after_finished;
+    // A referenced job template brings its own job settings, the docbot defaults only fill in
+    // what it leaves unset.
+    let base = job_template
+        .and_then(|job_template| job_template.spec)
+        .unwrap_or_default();
+    let mut job_spec = JobSpec {
+        // Set the job ttl after it finishes.
+        ttl_seconds_after_finished: base
+            .ttl_seconds_after_finished
+            .or(hook.pod_template().ttl_seconds_after_finished),
+        ..base
+    };
 
     if let Some(pod_template_spec) = template.template {
         if let Some(ref metadata) = pod_template_spec.metadata {
@@ -50,15 +286,34 @@ pub fn generate_from_template(
             )
         }
         // On Error reduce the back-off limit to 1 to stop k8s from re-trying the errored out jobs
-        job_spec.backoff_limit = Some(1)
+        job_spec.backoff_limit = job_spec.backoff_limit.or(Some(1))
+    }
+
+    if let Some(ref overrides) = hook.pod_template().job_spec {
+        job_spec.backoff_limit = overrides.backoff_limit.or(job_spec.backoff_limit);
+        job_spec.active_deadline_seconds = overrides
+            .active_deadline_seconds
+            .or(job_spec.active_deadline_seconds);
+        job_spec.parallelism = overrides.parallelism.or(job_spec.parallelism);
+        job_spec.completions = overrides.completions.or(job_spec.completions);
     }
     job.spec = Some(job_spec);
+
+    use_deployment_images(
+        &mut job,
+        &hook.pod_template().use_deployment_image,
+        deployment,
+    )?;
+    inject_deployment_context(&mut job, deployment);
+
     Ok(job)
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use docbot_crd::DeploymentHook;
+    use k8s_openapi::api::apps::v1::Deployment;
 
     fn example_pod_template() -> PodTemplate {
         let contents = r#"
@@ -112,11 +367,45 @@ spec:
         serde_yaml::from_str(contents).unwrap()
     }
 
+    fn example_deployment() -> Deployment {
+        let contents = r#"
+---
+apiVersion: apps/v1
+kind: Deployment
+metadata:
+  name: nginx-deployment
+  namespace: docbot-test
+  annotations:
+    deployment.kubernetes.io/revision: "7"
+  labels:
+    app: nginx
+    apps.mx.com/deploymenthook: finished
+spec:
+  replicas: 2
+  selector:
+    matchLabels:
+      app: nginx
+  template:
+    metadata:
+      labels:
+        app: nginx
+    spec:
+      containers:
+      - name: nginx
+        image: nginx:1.14.2
+      - name: istio-proxy
+        image: istio/proxyv2:1.16.0
+"#;
+
+        serde_yaml::from_str(contents).unwrap()
+    }
+
     #[test]
     fn generating_job_from_deployment_and_hook() {
         let template = example_pod_template();
         let hook = example_deployment_hook();
-        let job = generate_from_template(&hook, template).unwrap();
+        let deployment = example_deployment();
+        let job = generate_from_template(&hook, template, None, &deployment).unwrap();
 
         let expected_contents = r#"
 ---
@@ -124,8 +413,17 @@ apiVersion: batch/v1
 kind: Job
 metadata:
   generateName: docbot-hook-run-app-migrations-
+  annotations:
+    apps.mx.com/deployment-kind: Deployment
+    apps.mx.com/deployment-name: nginx-deployment
+    apps.mx.com/deployment-namespace: docbot-test
+    apps.mx.com/deployment-revision: "7"
+    apps.mx.com/pod-template-hash: ${HASH}
+    images.apps.mx.com/nginx: "nginx:1.14.2"
+    images.apps.mx.com/istio-proxy: "istio/proxyv2:1.16.0"
   labels:
     app: nginx
+    apps.mx.com/deploymenthook-name: run-app-migrations
   namespace: docbot-test
   ownerReferences:
   - apiVersion: "apps.mx.com/v1"
@@ -134,7 +432,7 @@ metadata:
     name: "run-app-migrations"
     uid: "1234"
 spec:
-  ttlSecondsAfterFinished: 259200
+  ttlSecondsAfterFinished: 86400
   backoffLimit: 1
   template:
     metadata:
@@ -147,6 +445,21 @@ spec:
             - sh
             - "-c"
             - "echo \"Running migrations...\"\necho \"Stopping istio...\"\ncurl -sf -XPOST http://127.0.0.1:15020/quitquitquit\necho \"Done\"\n"
+          env:
+            - name: DOCBOT_DEPLOYMENT_KIND
+              value: Deployment
+            - name: DOCBOT_DEPLOYMENT_NAME
+              value: nginx-deployment
+            - name: DOCBOT_DEPLOYMENT_NAMESPACE
+              value: docbot-test
+            - name: DOCBOT_DEPLOYMENT_REVISION
+              value: "7"
+            - name: DOCBOT_POD_TEMPLATE_HASH
+              value: ${HASH}
+            - name: DOCBOT_IMAGE_NGINX
+              value: "nginx:1.14.2"
+            - name: DOCBOT_IMAGE_ISTIO_PROXY
+              value: "istio/proxyv2:1.16.0"
           envFrom:
             - configMapRef:
                 name: config-nginx-test
@@ -154,9 +467,101 @@ spec:
           name: nginx
           ports:
             - containerPort: 80
-"#;
-        let expected_job: Job = serde_yaml::from_str(expected_contents).unwrap();
+"#
+        .replace(
+            "${HASH}",
+            &deployment.pod_template_hash().unwrap(),
+        );
+        let expected_job: Job = serde_yaml::from_str(&expected_contents).unwrap();
 
         assert_eq!(expected_job, job);
     }
+
+    #[test]
+    fn using_the_deployment_image() {
+        let template = example_pod_template();
+        let deployment = example_deployment();
+        let mut hook = example_deployment_hook();
+        hook.spec.template.use_deployment_image = serde_yaml::from_str(
+            r#"
+- container: nginx
+  deploymentContainer: istio-proxy
+"#,
+        )
+        .unwrap();
+
+        let job = generate_from_template(&hook, template.clone(), None, &deployment).unwrap();
+        let containers = job.spec.unwrap().template.spec.unwrap().containers;
+        assert_eq!(containers[0].image.as_deref(), Some("istio/proxyv2:1.16.0"));
+
+        // Referencing a container the deployment doesn't have is an error rather than
+        // silently running the image pinned in the template.
+        hook.spec.template.use_deployment_image[0].deployment_container = Some("web".into());
+        assert!(generate_from_template(&hook, template, None, &deployment).is_err());
+    }
+
+    #[test]
+    fn overriding_the_job_spec() {
+        let mut hook = example_deployment_hook();
+        hook.spec.template.job_spec = serde_yaml::from_str(
+            r#"
+activeDeadlineSeconds: 3600
+parallelism: 4
+completions: 8
+"#,
+        )
+        .unwrap();
+
+        let job_spec =
+            generate_from_template(&hook, example_pod_template(), None, &example_deployment())
+                .unwrap()
+                .spec
+                .unwrap();
+        assert_eq!(job_spec.backoff_limit, Some(1));
+        assert_eq!(job_spec.active_deadline_seconds, Some(3600));
+        assert_eq!(job_spec.parallelism, Some(4));
+        assert_eq!(job_spec.completions, Some(8));
+    }
+
+    #[test]
+    fn basing_the_job_on_a_job_template() {
+        let mut hook = example_deployment_hook();
+        hook.spec.template.job_spec = serde_yaml::from_str("parallelism: 2").unwrap();
+        let job_template: JobTemplateSpec = serde_yaml::from_str(
+            r#"
+metadata:
+  labels:
+    app: db-migrate
+spec:
+  backoffLimit: 4
+  ttlSecondsAfterFinished: 600
+  parallelism: 1
+  template:
+    spec:
+      restartPolicy: OnFailure
+      containers:
+      - name: migrate
+        image: migrate:1.0
+"#,
+        )
+        .unwrap();
+
+        let template = pod_template_from_job_template(&job_template, "docbot-test");
+        let job =
+            generate_from_template(&hook, template, Some(job_template), &example_deployment())
+                .unwrap();
+
+        assert_eq!(
+            job.metadata.labels.unwrap().get("app").map(String::as_str),
+            Some("db-migrate")
+        );
+        let job_spec = job.spec.unwrap();
+        assert_eq!(job_spec.backoff_limit, Some(4));
+        assert_eq!(job_spec.ttl_seconds_after_finished, Some(600));
+        assert_eq!(job_spec.parallelism, Some(2));
+        assert_eq!(
+            job_spec.template.spec.unwrap().restart_policy.as_deref(),
+            Some("OnFailure")
+        );
+    }
 }
@@ -0,0 +1,162 @@
+use crate::context::Context;
+use crate::events::EventType;
+use crate::job::triggering_deployment;
+use crate::metrics;
+use crate::utils::JobExt;
+use docbot_crd::HookPhase;
+use k8s_openapi::api::batch::v1::Job;
+use k8s_openapi::api::core::v1::ObjectReference;
+use kube::{
+    api::{Patch, PatchParams},
+    Api,
+};
+use serde_json::json;
+
+/// The finished phase the controller published an event for. Recorded on the job itself so
+/// the event is published once, even across restarts.
+const REPORTED_PHASE_ANNOTATION: &str = "apps.mx.com/reported-phase";
+
+/// The hook owning the job, as the subject of an event.
+fn hook_reference(job: &Job) -> Option<ObjectReference> {
+    let owner = job
+        .metadata
+        .owner_references
+        .iter()
+        .flatten()
+        .find(|owner| owner.kind == "DeploymentHook" || owner.kind == "ClusterDeploymentHook")?;
+
+    Some(ObjectReference {
+        api_version: Some(owner.api_version.clone()),
+        kind: Some(owner.kind.clone()),
+        name: Some(owner.name.clone()),
+        // Cluster hooks aren't namespaced, their jobs are.
+        namespace: match owner.kind.as_str() {
+            "DeploymentHook" => job.metadata.namespace.clone(),
+            _ => None,
+        },
+        uid: Some(owner.uid.clone()),
+        ..ObjectReference::default()
+    })
+}
+
+/// The event to publish for the job, if it finished since we last published one.
+fn unreported_event(job: &Job) -> Option<(HookPhase, EventType, &'static str)> {
+    let phase = job.hook_phase();
+    let event = match phase {
+        HookPhase::Succeeded => (phase, EventType::Normal, "JobSucceeded"),
+        HookPhase::Failed => (phase, EventType::Warning, "JobFailed"),
+        _ => return None,
+    };
+
+    let reported = job
+        .metadata
+        .annotations
+        .as_ref()
+        .and_then(|annotations| annotations.get(REPORTED_PHASE_ANNOTATION));
+    if reported.map(String::as_str) == Some(&format!("{phase:?}")) {
+        return None;
+    }
+
+    Some(event)
+}
+
+/// Publish `JobSucceeded` or `JobFailed` on the hook and deployment of a job once it finishes.
+pub async fn record_job_progress(
+    ctx: &Context,
+    job: &Job,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (phase, type_, reason) = match unreported_event(job) {
+        Some(event) => event,
+        None => return Ok(()),
+    };
+    let hook = match hook_reference(job) {
+        Some(hook) => hook,
+        None => return Ok(()),
+    };
+
+    // Mark the job first, a missed event is better than publishing it on every change.
+    let job_api: Api<Job> = Api::namespaced(
+        ctx.client.clone(),
+        job.metadata.namespace.as_deref().unwrap_or_default(),
+    );
+    job_api
+        .patch(
+            job.metadata.name.as_deref().unwrap_or_default(),
+            &PatchParams::default(),
+            &Patch::Merge(json!({
+                "metadata": {
+                    "annotations": { REPORTED_PHASE_ANNOTATION: format!("{phase:?}") }
+                }
+            })),
+        )
+        .await?;
+
+    let deployment = triggering_deployment(job);
+    let hook_name = match hook.namespace {
+        Some(ref namespace) => format!("{namespace}/{}", hook.name.as_deref().unwrap_or_default()),
+        None => hook.name.clone().unwrap_or_default(),
+    };
+    let outcome = match phase {
+        HookPhase::Succeeded => "succeeded",
+        _ => {
+            metrics::job_failed(hook.kind.as_deref().unwrap_or_default(), &hook_name);
+            "failed"
+        }
+    };
+    let mut message = format!(
+        "Job {} of hook {} {}",
+        job.metadata.name.as_deref().unwrap_or_default(),
+        hook.name.as_deref().unwrap_or_default(),
+        outcome
+    );
+    if let Some(ref deployment) = deployment {
+        message.push_str(&format!(
+            " for {} {}/{}",
+            deployment.kind.kind(),
+            deployment.namespace,
+            deployment.name
+        ));
+    }
+
+    ctx.recorder
+        .publish_for_hook(
+            hook,
+            deployment.map(|deployment| deployment.object_reference()),
+            type_,
+            reason,
+            message,
+        )
+        .await;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn job(condition: &str, reported: &str) -> Job {
+        serde_yaml::from_str(&format!(
+            r#"
+metadata:
+  name: docbot-hook-run-app-migrations-abcde
+  namespace: docbot-test
+  annotations:
+    apps.mx.com/reported-phase: "{reported}"
+status:
+  conditions: [{{type: {condition}, status: 'True'}}]
+"#
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn finished_jobs_are_reported_once() {
+        let reason = |job: &Job| unreported_event(job).map(|(_, _, reason)| reason);
+
+        assert_eq!(reason(&job("Complete", "")), Some("JobSucceeded"));
+        assert_eq!(reason(&job("Complete", "Succeeded")), None);
+        assert_eq!(reason(&job("Failed", "")), Some("JobFailed"));
+        assert_eq!(reason(&job("Suspended", "")), None);
+    }
+}
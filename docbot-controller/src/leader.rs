@@ -0,0 +1,324 @@
+use k8s_openapi::api::coordination::v1::{Lease, LeaseSpec};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{MicroTime, ObjectMeta};
+use k8s_openapi::chrono::Utc;
+use kube::{api::PostParams, client::Client, Api};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use tracing::info;
+
+/// Name of the `Lease` the replicas compete for.
+const LEASE_NAME: &str = "docbot-controller";
+/// How long a lease that isn't renewed keeps other replicas from taking over.
+const LEASE_DURATION: Duration = Duration::from_secs(15);
+/// How long the leader keeps leading while failing to renew the lease. Shorter than the lease
+/// duration so it stops before another replica can take over.
+const RENEW_DEADLINE: Duration = Duration::from_secs(10);
+const RETRY_PERIOD: Duration = Duration::from_secs(2);
+
+/// Whether this replica holds the lease. Clones share the same state.
+#[derive(Debug, Clone, Default)]
+pub struct Leadership {
+    leading: Arc<AtomicBool>,
+    acquired: Arc<Notify>,
+    /// When we last saw another replica renew the lease, by our own clock.
+    renewed: Arc<Mutex<Option<Instant>>>,
+}
+
+impl Leadership {
+    pub fn is_leader(&self) -> bool {
+        self.leading.load(Ordering::SeqCst)
+    }
+
+    /// Wait until this replica becomes the leader.
+    pub async fn acquired(&self) {
+        while !self.is_leader() {
+            self.acquired.notified().await;
+        }
+    }
+
+    /// Whether another replica renewed the lease after `instant`, meaning it was still leading
+    /// and handling rollouts at that point.
+    pub fn renewed_since(&self, instant: Instant) -> bool {
+        self.renewed
+            .lock()
+            .unwrap()
+            .is_some_and(|renewed| renewed > instant)
+    }
+
+    fn set_renewed(&self, renewed: Instant) {
+        self.renewed.lock().unwrap().replace(renewed);
+    }
+
+    fn set_leader(&self) {
+        self.leading.store(true, Ordering::SeqCst);
+        self.acquired.notify_one();
+    }
+
+    /// Stop leading right away, the controller exits once leader election stops.
+    fn step_down(&self) {
+        self.leading.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Identifies this replica in the lease, the pod name when running in a cluster.
+pub fn identity() -> String {
+    std::env::var("POD_NAME")
+        .or_else(|_| std::env::var("HOSTNAME"))
+        .unwrap_or_else(|_| format!("docbot-{}", std::process::id()))
+}
+
+/// Namespace of the lease, the namespace docbot runs in.
+pub fn namespace() -> String {
+    std::env::var("POD_NAMESPACE")
+        .ok()
+        .or_else(|| {
+            std::fs::read_to_string("/var/run/secrets/kubernetes.io/serviceaccount/namespace").ok()
+        })
+        .map(|namespace| namespace.trim().to_string())
+        .unwrap_or_else(|| "default".to_string())
+}
+
+/// Whether `identity` may take the lease. A lease held by another replica expires once it
+/// hasn't changed for its duration, measured by our own clock from when we first saw it so
+/// clock skew between replicas doesn't matter.
+fn is_available(
+    spec: &LeaseSpec,
+    identity: &str,
+    observed: &mut Option<(LeaseSpec, Instant)>,
+    now: Instant,
+) -> bool {
+    match spec.holder_identity.as_deref() {
+        None | Some("") => return true,
+        Some(holder) if holder == identity => return true,
+        Some(_) => {}
+    }
+
+    match observed {
+        Some((seen, since)) if seen == spec => {
+            let duration = spec
+                .lease_duration_seconds
+                .map(|seconds| Duration::from_secs(seconds.max(0) as u64))
+                .unwrap_or(LEASE_DURATION);
+            now.saturating_duration_since(*since) > duration
+        }
+        _ => {
+            *observed = Some((spec.clone(), now));
+            false
+        }
+    }
+}
+
+/// Take or renew the lease, returning whether we hold it afterwards.
+async fn try_acquire_or_renew(
+    api: &Api<Lease>,
+    identity: &str,
+    observed: &mut Option<(LeaseSpec, Instant)>,
+) -> Result<bool, kube::Error> {
+    let now = MicroTime(Utc::now());
+
+    let lease = match api.get(LEASE_NAME).await {
+        Ok(lease) => lease,
+        Err(kube::Error::Api(err)) if err.code == 404 => {
+            let lease = Lease {
+                metadata: ObjectMeta {
+                    name: Some(LEASE_NAME.to_string()),
+                    ..ObjectMeta::default()
+                },
+                spec: Some(LeaseSpec {
+                    holder_identity: Some(identity.to_string()),
+                    lease_duration_seconds: Some(LEASE_DURATION.as_secs() as i32),
+                    acquire_time: Some(now.clone()),
+                    renew_time: Some(now),
+                    lease_transitions: Some(0),
+                }),
+            };
+            return match api.create(&PostParams::default(), &lease).await {
+                Ok(_) => Ok(true),
+                Err(kube::Error::Api(err)) if err.code == 409 => Ok(false),
+                Err(err) => Err(err),
+            };
+        }
+        Err(err) => return Err(err),
+    };
+
+    let spec = lease.spec.clone().unwrap_or_default();
+    if !is_available(&spec, identity, observed, Instant::now()) {
+        return Ok(false);
+    }
+
+    let held = spec.holder_identity.as_deref() == Some(identity);
+    if !held {
+        info!(
+            "Lease {LEASE_NAME} held by {} expired, taking over",
+            spec.holder_identity.as_deref().unwrap_or_default()
+        );
+    }
+
+    let mut renewed = lease;
+    renewed.spec = Some(LeaseSpec {
+        holder_identity: Some(identity.to_string()),
+        lease_duration_seconds: Some(LEASE_DURATION.as_secs() as i32),
+        acquire_time: if held {
+            spec.acquire_time
+        } else {
+            Some(now.clone())
+        },
+        renew_time: Some(now),
+        lease_transitions: if held {
+            spec.lease_transitions
+        } else {
+            Some(spec.lease_transitions.unwrap_or_default() + 1)
+        },
+    });
+
+    // The resource version we read makes the replace fail when another replica got there first.
+    match api
+        .replace(LEASE_NAME, &PostParams::default(), &renewed)
+        .await
+    {
+        Ok(_) => Ok(true),
+        Err(kube::Error::Api(err)) if err.code == 409 => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+/// Compete for the lease in `namespace`, marking `leadership` once we hold it. Only returns
+/// once leadership is lost, as the leader can't tell which of its jobs another replica is
+/// about to create again.
+pub async fn run(
+    client: Client,
+    leadership: Leadership,
+    identity: String,
+    namespace: String,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let api: Api<Lease> = Api::namespaced(client, &namespace);
+    let mut observed = None;
+    let mut last_renewal = Instant::now();
+
+    info!("Competing for lease {namespace}/{LEASE_NAME} as {identity}");
+
+    loop {
+        // A request that hangs must not keep us leading past the deadline, the lease may have
+        // expired for the other replicas by then.
+        let attempt = Instant::now();
+        let deadline = if leadership.is_leader() {
+            RENEW_DEADLINE.saturating_sub(last_renewal.elapsed())
+        } else {
+            RENEW_DEADLINE
+        };
+        let result = tokio::time::timeout(
+            deadline,
+            try_acquire_or_renew(&api, &identity, &mut observed),
+        )
+        .await;
+
+        match result {
+            Ok(Ok(true)) => {
+                last_renewal = attempt;
+                if !leadership.is_leader() {
+                    info!("Became the leader as {identity}");
+                    leadership.set_leader();
+                }
+            }
+            Ok(Ok(false)) if leadership.is_leader() => {
+                leadership.step_down();
+                return Err(format!("Lease {namespace}/{LEASE_NAME} was taken over").into());
+            }
+            Ok(Ok(false)) => {
+                if let Some((_, renewed)) = &observed {
+                    leadership.set_renewed(*renewed);
+                }
+            }
+            Ok(Err(err)) => {
+                info!("Failed to acquire or renew lease {namespace}/{LEASE_NAME}, error: {err:?}");
+            }
+            Err(_) => {
+                info!("Acquiring or renewing lease {namespace}/{LEASE_NAME} timed out");
+            }
+        }
+
+        if leadership.is_leader() && last_renewal.elapsed() > RENEW_DEADLINE {
+            leadership.step_down();
+            return Err(format!(
+                "Failed to renew lease {namespace}/{LEASE_NAME} within {RENEW_DEADLINE:?}"
+            )
+            .into());
+        }
+
+        tokio::time::sleep(RETRY_PERIOD).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn held_by(holder: &str, renewed: &str) -> LeaseSpec {
+        serde_yaml::from_str(&format!(
+            r#"
+holderIdentity: "{holder}"
+leaseDurationSeconds: 15
+renewTime: "{renewed}"
+"#
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn leases_expire_once_they_stop_changing() {
+        let now = Instant::now();
+        let mut observed = None;
+
+        assert!(is_available(
+            &LeaseSpec::default(),
+            "docbot-b",
+            &mut observed,
+            now
+        ));
+        assert!(is_available(
+            &held_by("docbot-b", "2023-01-01T00:00:00.000000Z"),
+            "docbot-b",
+            &mut observed,
+            now
+        ));
+
+        let lease = held_by("docbot-a", "2023-01-01T00:00:00.000000Z");
+        assert!(!is_available(&lease, "docbot-b", &mut observed, now));
+        assert!(!is_available(
+            &lease,
+            "docbot-b",
+            &mut observed,
+            now + Duration::from_secs(10)
+        ));
+        assert!(is_available(
+            &lease,
+            "docbot-b",
+            &mut observed,
+            now + Duration::from_secs(16)
+        ));
+
+        // Renewing the lease starts the wait over.
+        let renewed = held_by("docbot-a", "2023-01-01T00:00:10.000000Z");
+        assert!(!is_available(
+            &renewed,
+            "docbot-b",
+            &mut observed,
+            now + Duration::from_secs(16)
+        ));
+    }
+
+    #[test]
+    fn followers_know_when_the_leader_last_renewed() {
+        let leadership = Leadership::default();
+        let finished = Instant::now();
+        assert!(!leadership.renewed_since(finished));
+
+        leadership.set_renewed(finished - Duration::from_secs(1));
+        assert!(!leadership.renewed_since(finished));
+
+        leadership.set_renewed(finished + Duration::from_secs(1));
+        assert!(leadership.renewed_since(finished));
+    }
+}
@@ -1,21 +1,54 @@
-use crate::cache::{CacheOp, DeploymentHookCache, DeploymentPodTemplateHashCache};
-use docbot_crd::{DeploymentHook, PodTemplateService};
-use futures::{StreamExt, TryStreamExt};
-use k8s_openapi::api::apps::v1::Deployment;
+use crate::cache::CacheOp;
+use crate::context::Context;
+use docbot_crd::{
+    rollout_api_resource, Hook, HookTrigger, JobTemplateService, PodTemplateService, RolloutPhase,
+    TargetKind, HOOK_NAME_LABEL,
+};
+use events::{EventType, Recorder};
+use futures::{future, StreamExt};
+use k8s_openapi::api::apps::v1::{DaemonSet, Deployment, StatefulSet};
 use k8s_openapi::api::batch::v1::Job;
+use k8s_openapi::api::batch::v1beta1::JobTemplateSpec;
+use k8s_openapi::api::core::v1::{Namespace, PodTemplate};
 use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+use k8s_openapi::chrono::{DateTime, Utc};
 use kube::{
     api::{ListParams, PostParams},
     client::Client,
-    core::WatchEvent,
-    Api,
+    core::DynamicObject,
+    Api, Resource,
+};
+use kube_runtime::{
+    reflector::{reflector, store::Writer},
+    utils::StreamBackoff,
+    watcher::{self, watcher},
 };
+use std::collections::BTreeMap;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
 use tracing::{error, info, Level};
-use utils::DeploymentExt;
+use utils::WorkloadExt;
 
 mod cache;
+mod concurrency;
+mod context;
+mod events;
+mod gate;
+mod health;
+mod history;
 mod job;
+mod job_events;
+mod leader;
+mod metrics;
+mod reconcile;
+mod retry;
+mod rollback;
+mod run_now;
+mod status;
+mod steps;
+mod suspend;
 mod utils;
+mod watch;
 
 // Helper to print namspace/name in a nice way since we do that a lot.
 trait ResourceFormatter {
@@ -28,162 +61,695 @@ impl ResourceFormatter for ObjectMeta {
             .name
             .clone()
             .unwrap_or_else(|| "__unknown__".to_string());
-        let namespace = self
-            .namespace
-            .clone()
-            .unwrap_or_else(|| "default".to_string());
 
-        format!("{namespace}/{name}")
+        // Cluster scoped resources don't have a namespace.
+        match self.namespace {
+            Some(ref namespace) => format!("{namespace}/{name}"),
+            None => name,
+        }
     }
 }
 
-async fn create_job_for_deployment_hook(
-    client: Client,
-    pod_template_service: PodTemplateService,
-    hook: &DeploymentHook,
+/// The pod template of the job, and the job template it is taken from when the hook references
+/// one.
+async fn resolve_templates<H: Hook>(
+    ctx: &Context,
+    hook: &H,
+    deployment: &impl WorkloadExt,
+    job_namespace: &str,
+) -> Result<(PodTemplate, Option<JobTemplateSpec>), Box<dyn std::error::Error>> {
+    let job_template = hook
+        .pod_template()
+        .get_job_template(job_namespace, &ctx.job_template_service)
+        .await?;
+    let pod_template = match job_template {
+        Some(ref job_template) => job::pod_template_from_job_template(job_template, job_namespace),
+        None => {
+            hook.pod_template()
+                .get_pod_template(job_namespace, deployment, ctx.pod_template_service.clone())
+                .await?
+        }
+    };
+
+    Ok((pod_template, job_template))
+}
+
+async fn create_job_for_deployment_hook<H: Hook>(
+    ctx: &Context,
+    hook: &H,
+    deployment: &impl WorkloadExt,
+    triggered_at: DateTime<Utc>,
 ) -> Result<(), Box<dyn std::error::Error>> {
+    let client = ctx.client.clone();
+    let job_namespace = hook.job_namespace(deployment);
+
     // Sometimes the API can fall behind or trigger things in different order. Allow up to N
     // seconds for the deploy hook's pod template to synchronize before triggering the job.
 
-    _ = pod_template_service
+    _ = ctx
+        .pod_template_service
         .wait_for_deployment_hook_pod_template_changes(
-            hook.metadata.formatted_name().clone(),
-            hook.has_embedded_pod_template(),
+            hook.meta().formatted_name().clone(),
+            hook.pod_template().has_embedded_pod_template(),
             std::time::Duration::from_secs(30),
         )
         .await;
 
-    let generated_job = job::generate_from_template(
-        hook,
-        hook.get_pod_template(pod_template_service.clone()).await?,
-    )?;
+    // Errors are turned into strings right away, the boxed error can't be held across the
+    // await of the event.
+    let templates = resolve_templates(ctx, hook, deployment, &job_namespace)
+        .await
+        .map_err(|err| err.to_string());
+    let (pod_template, job_template) = match templates {
+        Ok(templates) => templates,
+        Err(message) => {
+            ctx.recorder
+                .publish_for_hook(
+                    hook.object_ref(&()),
+                    Some(deployment.object_reference()),
+                    EventType::Warning,
+                    "TemplateNotFound",
+                    format!(
+                        "Could not resolve the template of {} {}: {}",
+                        H::kind(&()),
+                        hook.meta().formatted_name(),
+                        message
+                    ),
+                )
+                .await;
+            return Err(message.into());
+        }
+    };
+
+    let mut generated_job =
+        job::generate_from_template(hook, pod_template, job_template, deployment)?;
+
+    if hook.rollout_phase() == RolloutPhase::PreRollout {
+        gate::annotate_job(&mut generated_job, deployment);
+    }
+
+    if !concurrency::admit_job(&client, hook, &job_namespace).await? {
+        // A skipped pre-rollout run would otherwise keep the deployment paused for good.
+        if hook.rollout_phase() == RolloutPhase::PreRollout
+            && deployment.supports_pre_rollout_gate()
+        {
+            release_skipped_hook(ctx, hook, deployment).await?;
+        }
+        return Ok(());
+    }
 
     let job_api: Api<Job> = Api::namespaced(
         client.clone(),
-        &generated_job.metadata.namespace.as_ref().unwrap(),
+        generated_job.metadata.namespace.as_ref().unwrap(),
     );
 
-    job_api
+    let created_job = job_api
         .create(&PostParams::default(), &generated_job)
         .await?;
 
+    ctx.recorder
+        .publish_for_hook(
+            hook.object_ref(&()),
+            Some(deployment.object_reference()),
+            EventType::Normal,
+            "JobCreated",
+            format!(
+                "Created job {} for {} {}",
+                created_job.metadata.formatted_name(),
+                H::kind(&()),
+                hook.meta().formatted_name()
+            ),
+        )
+        .await;
+    metrics::job_created(
+        H::kind(&()).as_ref(),
+        &hook.meta().formatted_name(),
+        triggered_at,
+    );
+
+    // The job exists, so failing here must not send the run into the retries, which would create
+    // it a second time. The status catches up with the job's progress.
+    if let Err(err) = status::record_job_created(&client, hook, deployment, &created_job).await {
+        info!(
+            "Failed to record job {} in the status of {} {}, error: {:?}",
+            created_job.metadata.formatted_name(),
+            H::kind(&()),
+            hook.meta().formatted_name(),
+            err
+        );
+        metrics::status_update_failed(H::kind(&()).as_ref(), &hook.meta().formatted_name());
+    }
+
     Ok(())
 }
 
-async fn watch_for_new_deployments(
-    client: Client,
-    deployment_hook_cache: DeploymentHookCache,
-    pod_template_service: PodTemplateService,
-    deployment_cache: DeploymentPodTemplateHashCache,
+/// Stop holding the deployment for a pre-rollout hook whose run was skipped, and for the hooks
+/// running after it, which are never started now.
+async fn release_skipped_hook<H: Hook>(
+    ctx: &Context,
+    hook: &H,
+    deployment: &impl WorkloadExt,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let deployment_api: Api<Deployment> = Api::all(client.clone());
-    let params = ListParams::default().labels("apps.mx.com/deploymenthook");
+    gate::release_skipped(
+        &ctx.client,
+        &ctx.recorder,
+        hook,
+        deployment,
+        "an earlier job of the hook is still running",
+    )
+    .await?;
+
+    let namespace = deployment.meta().namespace.as_deref().unwrap_or("default");
+    let namespace_labels = ctx.namespace_cache.get(&ctx.client, namespace).await?;
+    let pre_rollout_hooks = triggered_hooks(
+        &ctx.hook_cache,
+        deployment,
+        &namespace_labels,
+        RolloutPhase::PreRollout,
+        HookTrigger::OnSuccess,
+    );
+    for dependent in steps::dependents(hook, &pre_rollout_hooks) {
+        gate::release_skipped(
+            &ctx.client,
+            &ctx.recorder,
+            &dependent,
+            deployment,
+            &format!(
+                "hook {} it runs after was skipped",
+                hook.meta().formatted_name()
+            ),
+        )
+        .await?;
+    }
 
-    let resource_version = deployment_api
-        .list(&params)
-        .await?
-        .metadata
-        .resource_version
-        .expect("invalid call");
+    Ok(())
+}
 
+fn spawn_job_for_hook<H: Hook, W: WorkloadExt>(ctx: &Context, hook: H, deployment: &W) {
     info!(
-        "Current Deployment API ResourceVersion: {}, Subscribing...",
-        &resource_version
+        "Creating a job for {} {} generated by {} {}",
+        H::kind(&()),
+        hook.meta().formatted_name(),
+        deployment.target_kind().kind(),
+        deployment.meta().formatted_name()
     );
 
-    let mut stream = deployment_api
-        .watch(&params, &resource_version)
-        .await?
-        .boxed();
+    // Spawn the task that generates a job because there contains logic to wait for
+    // up to N seconds which would block this code path otherwise.
+    tokio::spawn({
+        let ctx = ctx.clone();
+        let deployment = deployment.clone();
+        async move {
+            metrics::hook_triggered(H::kind(&()).as_ref(), &hook.meta().formatted_name());
+            ctx.recorder
+                .publish_for_hook(
+                    hook.object_ref(&()),
+                    Some(deployment.object_reference()),
+                    EventType::Normal,
+                    "HookTriggered",
+                    format!(
+                        "{} {} triggered by {} {}",
+                        H::kind(&()),
+                        hook.meta().formatted_name(),
+                        deployment.target_kind().kind(),
+                        deployment.meta().formatted_name()
+                    ),
+                )
+                .await;
+
+            retry::create_job_with_retries(&ctx, &hook, &deployment, 0, Utc::now()).await
+        }
+    });
+}
 
-    while let Some(event) = stream.try_next().await? {
-        match event {
-            WatchEvent::Added(deployment) | WatchEvent::Modified(deployment) => {
-                // If the deployment hasn't finished, we should skip.
-                if !deployment.did_successfully_deploy() {
-                    continue;
-                }
+/// The hooks of kind `H` matching the deployment that run in `phase`, and for `PostRollout`
+/// hooks on `trigger`.
+fn triggered_hooks<H: Hook>(
+    cache: &cache::HookCache<H>,
+    deployment: &impl WorkloadExt,
+    namespace_labels: &BTreeMap<String, String>,
+    phase: RolloutPhase,
+    trigger: HookTrigger,
+) -> Vec<H> {
+    cache
+        .find_by_matching_deployment(deployment, namespace_labels)
+        .into_iter()
+        .filter(|hook| {
+            hook.rollout_phase() == phase
+                && (phase == RolloutPhase::PreRollout || hook.trigger() == trigger)
+        })
+        .collect()
+}
 
-                // With a successfully deployed deployment, check to see if we've seen
-                // this pod template before. If we have, then it is likely a pod of an
-                // existing deployment was restarted, or scaled up or down.
-                if let CacheOp::Unchanged = deployment_cache.update_cache(&deployment) {
-                    info!(
-                        "Skipping deployment {} because pod template was not modified",
-                        deployment.metadata.formatted_name()
-                    );
-                    continue;
-                }
-                let matching_deployment =
-                    deployment_hook_cache.find_by_matching_deployment(&deployment);
-
-                for hook in matching_deployment {
-                    info!(
-                        "Creating a job for hook {} generated by deployment {}",
-                        hook.metadata.formatted_name(),
-                        deployment.metadata.formatted_name()
-                    );
-
-                    // Spawn the task that generates a job because there contains logic to wait for
-                    // up to N seconds which would block this code path otherwise.
-                    tokio::spawn({
-                        let client = client.clone();
-                        let pod_template_service = pod_template_service.clone();
-                        let deployment = deployment.clone();
-                        async move {
-                            if let Err(err) = create_job_for_deployment_hook(
-                                client.clone(),
-                                pod_template_service.clone(),
-                                &hook,
-                            )
-                            .await
-                            {
-                                info!(
-                                    "Failed to create a job for hook {} generated by deployment {}, error: {:?}",
-                                    hook.metadata.formatted_name(),
-                                    deployment.metadata.formatted_name(),
-                                    err
-                                );
-                            }
-                        }
-                    });
-                }
+/// Start the hooks that don't run after any of the other hooks, the rest is started once those
+/// succeed. Suspended hooks are recorded to run once they are resumed instead, holding back the
+/// hooks running after them. Each run is claimed in the hook status first, so a rollout that is
+/// handled twice, e.g. by a new leader, starts it only once.
+async fn start_first_steps<H: Hook, W: WorkloadExt>(ctx: &Context, hooks: Vec<H>, deployment: &W) {
+    let hash = deployment.pod_template_hash().unwrap_or_default();
+    for hook in suspend::skip_suspended(ctx, steps::first_steps(hooks), deployment) {
+        match status::claim_run(&ctx.client, &hook, deployment, &hash).await {
+            Ok(true) => spawn_job_for_hook(ctx, hook, deployment),
+            Ok(false) => info!(
+                "{} {} already ran for {} {}",
+                H::kind(&()),
+                hook.meta().formatted_name(),
+                deployment.target_kind().kind(),
+                deployment.meta().formatted_name()
+            ),
+            // Rather run the hook twice than not at all.
+            Err(err) => {
+                info!(
+                    "Failed to claim the run of {} {}, starting it anyway, error: {:?}",
+                    H::kind(&()),
+                    hook.meta().formatted_name(),
+                    err
+                );
+                spawn_job_for_hook(ctx, hook, deployment);
             }
-            _ => { /* ignore */ }
         }
     }
+}
 
-    Ok(())
+/// Start the hooks triggered by a rollout, leaving out the ones in a `runAfter` cycle.
+async fn start_hooks<H: Hook, W: WorkloadExt>(ctx: &Context, hooks: Vec<H>, deployment: &W) {
+    let hooks = steps::skip_cycles(ctx, hooks, deployment).await;
+    start_first_steps(ctx, hooks, deployment).await;
 }
 
-async fn watch_for_deployment_hook_changes(
-    client: Client,
-    cache: DeploymentHookCache,
+/// Run the hooks matching a deployment whose rollout started, finished or failed. Errors are
+/// retried by the reconciler of the deployment.
+async fn handle_deployment<W: WorkloadExt>(
+    ctx: &Context,
+    deployment: W,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let hooks_api: Api<DeploymentHook> = Api::all(client.clone());
-    let params = ListParams::default();
+    let namespace = deployment.meta().namespace.as_deref().unwrap_or("default");
+    let namespace_labels = match ctx.namespace_cache.get(&ctx.client, namespace).await {
+        Ok(labels) => labels,
+        Err(err) => {
+            return Err(format!("Failed to look up labels of namespace {namespace}: {err}").into())
+        }
+    };
 
-    let resource_version = hooks_api
-        .list(&params)
-        .await?
-        .metadata
-        .resource_version
-        .expect("invalid call");
+    // Pre-rollout hooks have to run before the new pod template rolls out, so
+    // they are handled as soon as a new pod template shows up. Only workloads
+    // that can be paused can be held back.
+    if gate::needs_gate(&deployment, &ctx.deployment_cache) {
+        let pre_rollout_hooks = triggered_hooks(
+            &ctx.hook_cache,
+            &deployment,
+            &namespace_labels,
+            RolloutPhase::PreRollout,
+            HookTrigger::OnSuccess,
+        );
+        let pre_rollout_hooks = steps::skip_cycles(ctx, pre_rollout_hooks, &deployment).await;
+        let pre_rollout_cluster_hooks = triggered_hooks(
+            &ctx.cluster_hook_cache,
+            &deployment,
+            &namespace_labels,
+            RolloutPhase::PreRollout,
+            HookTrigger::OnSuccess,
+        );
+        let pre_rollout_cluster_hooks =
+            steps::skip_cycles(ctx, pre_rollout_cluster_hooks, &deployment).await;
 
-    info!(
-        "Current Deployment Hook API ResrouceVersion: {}, Subscribing...",
-        &resource_version
+        // The rollout doesn't wait for suspended hooks, nor for the hooks they hold back.
+        let gating_hooks = steps::unsuspended(&pre_rollout_hooks);
+        let gating_cluster_hooks = steps::unsuspended(&pre_rollout_cluster_hooks);
+        let pending: Vec<String> = gating_hooks
+            .iter()
+            .map(|hook| hook.meta().formatted_name())
+            .chain(
+                gating_cluster_hooks
+                    .iter()
+                    .map(|hook| hook.meta().formatted_name()),
+            )
+            .collect();
+
+        if !pending.is_empty() {
+            if let Err(err) =
+                gate::pause_deployment(&ctx.client, &ctx.recorder, &deployment, &pending).await
+            {
+                return Err(format!(
+                    "Failed to pause deployment {} for pre-rollout hooks: {}",
+                    deployment.meta().formatted_name(),
+                    err
+                )
+                .into());
+            }
+
+            for hook in &gating_hooks {
+                gate::record_blocked(&ctx.client, hook, &deployment).await;
+            }
+            for hook in &gating_cluster_hooks {
+                gate::record_blocked(&ctx.client, hook, &deployment).await;
+            }
+        }
+
+        start_first_steps(ctx, pre_rollout_hooks, &deployment).await;
+        start_first_steps(ctx, pre_rollout_cluster_hooks, &deployment).await;
+        if !pending.is_empty() {
+            return Ok(());
+        }
+    }
+
+    // Failed rollouts trigger the failure hooks once per pod template, the same
+    // way successful ones are deduplicated below.
+    if deployment.did_fail_to_deploy() {
+        if let CacheOp::Unchanged = ctx.failed_deployment_cache.update_cache(&deployment) {
+            return Ok(());
+        }
+
+        info!(
+            "{} {} failed to roll out",
+            deployment.target_kind().kind(),
+            deployment.meta().formatted_name()
+        );
+
+        let failure_hooks = triggered_hooks(
+            &ctx.hook_cache,
+            &deployment,
+            &namespace_labels,
+            RolloutPhase::PostRollout,
+            HookTrigger::OnFailure,
+        );
+        let failure_cluster_hooks = triggered_hooks(
+            &ctx.cluster_hook_cache,
+            &deployment,
+            &namespace_labels,
+            RolloutPhase::PostRollout,
+            HookTrigger::OnFailure,
+        );
+        start_hooks(ctx, failure_hooks, &deployment).await;
+        start_hooks(ctx, failure_cluster_hooks, &deployment).await;
+        return Ok(());
+    }
+
+    // If the deployment hasn't finished, we should skip.
+    if !deployment.did_successfully_deploy() {
+        return Ok(());
+    }
+
+    let hooks = triggered_hooks(
+        &ctx.hook_cache,
+        &deployment,
+        &namespace_labels,
+        RolloutPhase::PostRollout,
+        HookTrigger::OnSuccess,
+    );
+    let cluster_hooks = triggered_hooks(
+        &ctx.cluster_hook_cache,
+        &deployment,
+        &namespace_labels,
+        RolloutPhase::PostRollout,
+        HookTrigger::OnSuccess,
     );
 
-    let mut stream = hooks_api.watch(&params, &resource_version).await?.boxed();
+    // With a successfully deployed deployment, check to see if we've seen
+    // this pod template before. If we have, then it is likely a pod of an
+    // existing deployment was restarted, or scaled up or down.
+    let new_generation = ctx.deployment_cache.is_new_generation(&deployment);
+    if let CacheOp::Unchanged = ctx.deployment_cache.update_cache(&deployment) {
+        info!(
+            "Skipping deployment {} because pod template was not modified",
+            deployment.meta().formatted_name()
+        );
+
+        // Only report changes to the spec, like scaling, rather than every status update.
+        if new_generation {
+            let hooks = hooks.iter().map(|hook| hook.object_ref(&()));
+            let cluster_hooks = cluster_hooks.iter().map(|hook| hook.object_ref(&()));
 
-    while let Some(_event) = stream.try_next().await? {
-        info!("Refreshing deployment hook cache.");
-        cache.refresh(&client).await?;
+            for hook in hooks.chain(cluster_hooks) {
+                ctx.recorder
+                    .publish_for_hook(
+                        hook.clone(),
+                        Some(deployment.object_reference()),
+                        EventType::Normal,
+                        "SkippedUnchangedTemplate",
+                        format!(
+                            "Not running hook {} because the pod template of {} {} did not change",
+                            hook.name.as_deref().unwrap_or_default(),
+                            deployment.target_kind().kind(),
+                            deployment.meta().formatted_name()
+                        ),
+                    )
+                    .await;
+            }
+        }
+        return Ok(());
+    }
+
+    start_hooks(ctx, hooks, &deployment).await;
+    start_hooks(ctx, cluster_hooks, &deployment).await;
+
+    Ok(())
+}
+
+/// Argo Rollouts are only watched when the `Rollout` custom resource is installed.
+async fn has_argo_rollouts(client: &Client) -> bool {
+    match client
+        .list_api_group_resources(TargetKind::Rollout.api_version())
+        .await
+    {
+        Ok(resources) => resources
+            .resources
+            .iter()
+            .any(|resource| resource.kind == TargetKind::Rollout.kind()),
+        Err(_) => false,
+    }
+}
+
+/// Every kind of workload hooks can target.
+#[derive(Clone)]
+struct WorkloadApis {
+    deployments: Api<Deployment>,
+    statefulsets: Api<StatefulSet>,
+    daemonsets: Api<DaemonSet>,
+    rollouts: Option<Api<DynamicObject>>,
+}
+
+impl WorkloadApis {
+    async fn new(client: &Client) -> Self {
+        let rollouts = if has_argo_rollouts(client).await {
+            Some(Api::all_with(client.clone(), &rollout_api_resource()))
+        } else {
+            info!("Argo Rollouts is not installed, not watching rollouts.");
+            None
+        };
+
+        Self {
+            deployments: Api::all(client.clone()),
+            statefulsets: Api::all(client.clone()),
+            daemonsets: Api::all(client.clone()),
+            rollouts,
+        }
+    }
+}
+
+/// Record the pod template of every workload whose rollout already finished, so only rollouts
+/// finishing from now on trigger hooks.
+async fn prime_deployment_caches(
+    apis: &WorkloadApis,
+    template_cache: &cache::DeploymentPodTemplateHashCache,
+    failed_template_cache: &cache::DeploymentPodTemplateHashCache,
+) -> Result<(), Box<dyn std::error::Error>> {
+    template_cache.refresh(&apis.deployments).await?;
+    template_cache.refresh(&apis.statefulsets).await?;
+    template_cache.refresh(&apis.daemonsets).await?;
+    if let Some(ref rollouts) = apis.rollouts {
+        template_cache.refresh(rollouts).await?;
+    }
+
+    failed_template_cache
+        .refresh_failed(&apis.deployments)
+        .await?;
+    if let Some(ref rollouts) = apis.rollouts {
+        failed_template_cache.refresh_failed(rollouts).await?;
     }
 
     Ok(())
 }
+
+/// Report a spec the hook can't honour, and run the hook now or replay its suspended runs when
+/// asked to. Only the leader acts on hooks, the other replicas only keep their caches warm.
+async fn handle_hook_change<H: Hook>(ctx: &Context, hook: &H) {
+    if !ctx.leadership.is_leader() {
+        return;
+    }
+
+    if let Err(err) = status::record_spec_problem(&ctx.client, &ctx.recorder, hook).await {
+        info!(
+            "Failed to validate {} {}, error: {:?}",
+            H::kind(&()),
+            hook.meta().formatted_name(),
+            err
+        );
+    }
+
+    if let Err(err) = run_now::handle_hook(ctx, hook).await {
+        info!(
+            "Failed to run {} {} now, error: {:?}",
+            H::kind(&()),
+            hook.meta().formatted_name(),
+            err
+        );
+    }
+
+    if let Err(err) = suspend::handle_hook(ctx, hook).await {
+        info!(
+            "Failed to resume {} {}, error: {:?}",
+            H::kind(&()),
+            hook.meta().formatted_name(),
+            err
+        );
+    }
+}
+
+/// Keep the hook cache in sync through its reflector and handle every hook as it changes,
+/// signalling `primed` once the hooks are first listed. The watcher only re-lists when its
+/// watch falls out of the api server's window.
+async fn watch_for_deployment_hook_changes<H: Hook>(
+    ctx: Context,
+    writer: Writer<H>,
+    primed: oneshot::Sender<()>,
+) {
+    let hooks_api: Api<H> = Api::all(ctx.client.clone());
+    let mut primed = Some(primed);
+    let mut stream = watch::observed(
+        StreamBackoff::new(
+            reflector(writer, watcher(hooks_api, ListParams::default())),
+            watcher::default_backoff(),
+        ),
+        ctx.health.clone(),
+        H::kind(&()).to_string(),
+    )
+    .boxed();
+
+    while let Some(event) = stream.next().await {
+        // Errors are reported by `observed`, the watcher recovers from them.
+        let event = match event {
+            Ok(event) => event,
+            Err(_) => continue,
+        };
+
+        if let watcher::Event::Restarted(_) = event {
+            if let Some(primed) = primed.take() {
+                _ = primed.send(());
+            }
+        }
+
+        // A re-list also catches up on the hooks that were triggered or resumed while we
+        // weren't watching.
+        for hook in event.into_iter_applied() {
+            handle_hook_change(&ctx, &hook).await;
+        }
+    }
+}
+
+/// Keep the namespace cache in sync through its reflector, signalling `primed` once the
+/// namespaces are first listed.
+async fn watch_namespaces(ctx: Context, writer: Writer<Namespace>, primed: oneshot::Sender<()>) {
+    let namespace_api: Api<Namespace> = Api::all(ctx.client.clone());
+    let mut primed = Some(primed);
+    let mut stream = watch::observed(
+        StreamBackoff::new(
+            reflector(writer, watcher(namespace_api, ListParams::default())),
+            watcher::default_backoff(),
+        ),
+        ctx.health.clone(),
+        "Namespace".to_string(),
+    )
+    .boxed();
+
+    while let Some(event) = stream.next().await {
+        if let Ok(watcher::Event::Restarted(_)) = event {
+            if let Some(primed) = primed.take() {
+                _ = primed.send(());
+            }
+        }
+    }
+}
+
+async fn watch_for_hook_jobs(ctx: Context) {
+    let job_api: Api<Job> = Api::all(ctx.client.clone());
+    let params = ListParams::default().labels(HOOK_NAME_LABEL);
+    let mut stream = watch::observed(
+        StreamBackoff::new(watcher(job_api, params), watcher::default_backoff()),
+        ctx.health.clone(),
+        "Job".to_string(),
+    )
+    .boxed();
+
+    while let Some(event) = stream.next().await {
+        match event {
+            Ok(watcher::Event::Applied(job)) => handle_job(&ctx, &job).await,
+            // The jobs that changed while we weren't watching, e.g. before we became the leader,
+            // are only in the list. Handling a job is idempotent, so the ones handled already
+            // don't run anything twice.
+            Ok(watcher::Event::Restarted(jobs)) => {
+                for job in &jobs {
+                    handle_job(&ctx, job).await;
+                }
+            }
+            // Errors are reported by `observed`, the watcher recovers from them.
+            _ => { /* ignore */ }
+        }
+    }
+}
+
+/// Reflect the progress of a job onto its hook, the pre-rollout gate, the hooks running after
+/// it and the job history.
+async fn handle_job(ctx: &Context, job: &Job) {
+    if let Err(err) = status::record_job_progress(&ctx.client, job).await {
+        info!(
+            "Failed to update hook status for job {}, error: {:?}",
+            job.metadata.formatted_name(),
+            err
+        );
+    }
+
+    if let Err(err) = gate::record_job_progress(&ctx.client, &ctx.recorder, job).await {
+        info!(
+            "Failed to update the pre-rollout gate for job {}, error: {:?}",
+            job.metadata.formatted_name(),
+            err
+        );
+    }
+
+    if let Err(err) = rollback::record_job_progress(ctx, job).await {
+        info!(
+            "Failed to roll back the deployment of job {}, error: {:?}",
+            job.metadata.formatted_name(),
+            err
+        );
+    }
+
+    if let Err(err) = steps::record_job_progress(ctx, job).await {
+        info!(
+            "Failed to start the hooks running after job {}, error: {:?}",
+            job.metadata.formatted_name(),
+            err
+        );
+    }
+
+    if let Err(err) = job_events::record_job_progress(ctx, job).await {
+        info!(
+            "Failed to publish the events of job {}, error: {:?}",
+            job.metadata.formatted_name(),
+            err
+        );
+    }
+
+    if let Err(err) = history::record_job_progress(ctx, job).await {
+        info!(
+            "Failed to prune the job history of job {}, error: {:?}",
+            job.metadata.formatted_name(),
+            err
+        );
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // construct a subscriber that prints formatted traces to stdout
@@ -205,92 +771,278 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .await
         .expect("Expected a valid KUBECONFIG environment variable.");
 
-    // Prime the deployhook cache
-    let cache = cache::DeploymentHookCache::default();
-    cache.refresh(&client).await?;
+    // Serve the probes while the caches are primed, so the pod isn't restarted in the meantime
+    let liveness_window = std::env::var("DOCBOT_LIVENESS_WINDOW_SECONDS")
+        .ok()
+        .and_then(|seconds| seconds.parse().ok())
+        .unwrap_or(600);
+    let health = health::Health::new(std::time::Duration::from_secs(liveness_window));
 
-    // Prime the deployment cache
+    // The long running tasks, the controller exits as soon as one of them stops
+    let mut tasks: Vec<(&str, JoinHandle<()>)> = Vec::new();
+
+    tasks.push((
+        "health probe server",
+        tokio::spawn({
+            let health = health.clone();
+            let addr =
+                std::env::var("DOCBOT_HEALTH_ADDR").unwrap_or_else(|_| "0.0.0.0:8081".into());
+
+            async move {
+                let addr = match addr.parse() {
+                    Ok(addr) => addr,
+                    Err(err) => {
+                        error!("Invalid DOCBOT_HEALTH_ADDR {addr}: {err}");
+                        return;
+                    }
+                };
+                info!("Serving health probes on {addr}");
+                if let Err(err) = health::serve(health, addr).await {
+                    error!("Health probe server failed: {err:?}");
+                }
+            }
+        }),
+    ));
+
+    // The hook and namespace caches are filled by their watchers once they start
+    let (cache, hook_writer) = cache::DeploymentHookCache::new();
+    let (cluster_cache, cluster_hook_writer) = cache::ClusterDeploymentHookCache::new();
+    let (namespace_cache, namespace_writer) = cache::NamespaceLabelCache::new();
+
+    let workload_apis = WorkloadApis::new(&client).await;
     let template_cache = cache::DeploymentPodTemplateHashCache::default();
-    template_cache.refresh(&client).await?;
+    let failed_template_cache = cache::DeploymentPodTemplateHashCache::default();
 
     let pod_template_service = PodTemplateService::new(client.clone());
+    let job_template_service = JobTemplateService::new(client.clone());
+    let recorder = Recorder::new(client.clone());
+    let leadership = leader::Leadership::default();
+
+    let ctx = Context {
+        client: client.clone(),
+        hook_cache: cache.clone(),
+        cluster_hook_cache: cluster_cache.clone(),
+        pod_template_service: pod_template_service.clone(),
+        job_template_service: job_template_service.clone(),
+        deployment_cache: template_cache.clone(),
+        failed_deployment_cache: failed_template_cache.clone(),
+        namespace_cache,
+        recorder: recorder.clone(),
+        retry_queue: retry::RetryQueue::default(),
+        health: health.clone(),
+        leadership: leadership.clone(),
+    };
+
+    // Watch for deployment hook changes
+    let (hooks_primed, hooks_listed) = oneshot::channel();
+    tasks.push((
+        "DeploymentHook watcher",
+        tokio::spawn(watch_for_deployment_hook_changes(
+            ctx.clone(),
+            hook_writer,
+            hooks_primed,
+        )),
+    ));
+
+    // Watch for cluster deployment hook changes
+    let (cluster_hooks_primed, cluster_hooks_listed) = oneshot::channel();
+    tasks.push((
+        "ClusterDeploymentHook watcher",
+        tokio::spawn(watch_for_deployment_hook_changes(
+            ctx.clone(),
+            cluster_hook_writer,
+            cluster_hooks_primed,
+        )),
+    ));
+
+    // Watch the namespaces, whose labels hooks with a namespace selector match
+    let (namespaces_primed, namespaces_listed) = oneshot::channel();
+    tasks.push((
+        "Namespace watcher",
+        tokio::spawn(watch_namespaces(
+            ctx.clone(),
+            namespace_writer,
+            namespaces_primed,
+        )),
+    ));
+
+    // Prime the hook and namespace caches
+    _ = hooks_listed.await;
+    _ = cluster_hooks_listed.await;
+    _ = namespaces_listed.await;
+
+    // Prime the deployment caches
+    prime_deployment_caches(&workload_apis, &template_cache, &failed_template_cache).await?;
+
+    health.set_ready();
 
     // Watch pod template changes for better data... sometimes the API can be stale
-    tokio::spawn({
-        let pod_template_service = pod_template_service.clone();
+    tasks.push((
+        "PodTemplate watcher",
+        tokio::spawn({
+            let pod_template_service = pod_template_service.clone();
 
-        async move {
-            loop {
-                if let Err(err) = pod_template_service.watch_for_changes().await {
-                    error!("Found error while watching pod template changes: {:?}", err);
+            let health = health.clone();
+
+            async move {
+                let mut events = watch::observed(
+                    pod_template_service.changes(),
+                    health,
+                    "PodTemplate".to_string(),
+                )
+                .boxed();
+                while let Some(event) = events.next().await {
+                    if let Ok(event) = event {
+                        pod_template_service.record_change(event).await;
+                    }
                 }
             }
-        }
-    });
+        }),
+    ));
 
-    // Refresh the cache every minute
-    tokio::spawn({
-        let cache = cache.clone();
-        let client = client.clone();
+    // Keep the cron jobs used as job templates up to date
+    tasks.push((
+        "CronJob watcher",
+        tokio::spawn({
+            let job_template_service = job_template_service.clone();
 
-        async move {
-            loop {
-                tokio::time::sleep(std::time::Duration::from_secs(60)).await;
+            let health = health.clone();
 
-                info!("Refreshing deployment hook cache.");
-                if let Err(err) = cache.refresh(&client).await {
-                    info!("Failed to refresh the deployment hooks cache: {:?}", err);
+            async move {
+                let mut events = watch::observed(
+                    job_template_service.changes(),
+                    health,
+                    "CronJob".to_string(),
+                )
+                .boxed();
+                while let Some(event) = events.next().await {
+                    if let Ok(event) = event {
+                        job_template_service.record_change(event).await;
+                    }
                 }
             }
-        }
-    });
+        }),
+    ));
 
-    tokio::spawn({
-        let cache = cache.clone();
-        let client = client.clone();
+    #[cfg(feature = "metrics")]
+    tasks.push((
+        "metrics server",
+        tokio::spawn({
+            let ctx = ctx.clone();
+            let addr =
+                std::env::var("DOCBOT_METRICS_ADDR").unwrap_or_else(|_| "0.0.0.0:8080".into());
 
-        async move {
-            // Watch for deployment hook changes
-            loop {
-                if let Err(err) =
-                    watch_for_deployment_hook_changes(client.clone(), cache.clone()).await
-                {
-                    info!("Error while watching deployment hook changes: {err:?}");
+            async move {
+                let addr = match addr.parse() {
+                    Ok(addr) => addr,
+                    Err(err) => {
+                        error!("Invalid DOCBOT_METRICS_ADDR {addr}: {err}");
+                        return;
+                    }
+                };
+                info!("Serving metrics on {addr}");
+                if let Err(err) = metrics::serve(ctx, addr).await {
+                    error!("Metrics server failed: {err:?}");
                 }
-
-                info!("DeploymentHook watcher finished or expired, restarting...");
-                tokio::time::sleep(std::time::Duration::from_secs(5)).await;
             }
-        }
-    });
+        }),
+    ));
 
-    tokio::spawn({
-        let cache = cache.clone();
-        let client = client.clone();
-        // let pod_template_service = pod_template_service.clone();
+    // Reconcile every kind of workload hooks can target. Followers only record the rollouts
+    // that finished, so they can take over from where the leader was
+    tasks.push((
+        "Deployment controller",
+        tokio::spawn(reconcile::run_workload_controller(
+            ctx.clone(),
+            TargetKind::Deployment,
+            workload_apis.deployments,
+            (),
+        )),
+    ));
+    tasks.push((
+        "StatefulSet controller",
+        tokio::spawn(reconcile::run_workload_controller(
+            ctx.clone(),
+            TargetKind::StatefulSet,
+            workload_apis.statefulsets,
+            (),
+        )),
+    ));
+    tasks.push((
+        "DaemonSet controller",
+        tokio::spawn(reconcile::run_workload_controller(
+            ctx.clone(),
+            TargetKind::DaemonSet,
+            workload_apis.daemonsets,
+            (),
+        )),
+    ));
+    if let Some(rollout_api) = workload_apis.rollouts {
+        tasks.push((
+            "Rollout controller",
+            tokio::spawn(reconcile::run_workload_controller(
+                ctx.clone(),
+                TargetKind::Rollout,
+                rollout_api,
+                rollout_api_resource(),
+            )),
+        ));
+    }
 
-        async move {
-            // Watch for deployment changes
-            loop {
-                info!("Deployment watcher starting...");
-                tokio::time::sleep(std::time::Duration::from_secs(5)).await;
-                if let Err(err) = watch_for_new_deployments(
-                    client.clone(),
-                    cache.clone(),
-                    pod_template_service.clone(),
-                    template_cache.clone(),
-                )
-                .await
+    // Only one replica creates jobs, the others wait with warm caches to take over. The leader
+    // stops once it loses the lease.
+    tasks.push((
+        "leader election",
+        tokio::spawn({
+            let client = client.clone();
+            let leadership = leadership.clone();
+
+            async move {
+                if let Err(err) =
+                    leader::run(client, leadership, leader::identity(), leader::namespace()).await
                 {
-                    info!("Error while watching deployment hook changes: {err:?}");
+                    error!("Lost leadership: {err}");
                 }
+            }
+        }),
+    ));
+
+    tasks.push((
+        "Job watcher",
+        tokio::spawn({
+            let ctx = ctx.clone();
+
+            async move {
+                ctx.leadership.acquired().await;
+
+                // Pick up the job creations that were still being retried before a restart or
+                // failover
+                retry::resume(&ctx);
+
+                // Catch up on the hooks that were triggered or resumed while we weren't leading
+                tokio::spawn({
+                    let ctx = ctx.clone();
 
-                info!("Deployment watcher finished or expired, restarting...");
+                    async move {
+                        for hook in ctx.hook_cache.all() {
+                            handle_hook_change(&ctx, &hook).await;
+                        }
+                        for hook in ctx.cluster_hook_cache.all() {
+                            handle_hook_change(&ctx, &hook).await;
+                        }
+                    }
+                });
+
+                // Watch the jobs we created so their progress shows up in the hook status
+                watch_for_hook_jobs(ctx).await;
             }
-        }
-    });
+        }),
+    ));
 
-    loop {
-        tokio::time::sleep(std::time::Duration::from_secs(1)).await
+    let (names, handles): (Vec<&str>, Vec<JoinHandle<()>>) = tasks.into_iter().unzip();
+    let (result, index, _) = future::select_all(handles).await;
+    match result {
+        Ok(()) => Err(format!("{} stopped, exiting", names[index]).into()),
+        Err(err) => Err(format!("{} failed, exiting: {err}", names[index]).into()),
     }
 }
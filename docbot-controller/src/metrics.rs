@@ -0,0 +1,206 @@
+// Metrics of the controller, registered with the default Prometheus registry and served on
+// `/metrics` when the `metrics` feature is enabled. Without it recording a metric does nothing.
+
+#[cfg(feature = "metrics")]
+use crate::context::Context;
+#[cfg(feature = "metrics")]
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use k8s_openapi::chrono::{DateTime, Utc};
+#[cfg(feature = "metrics")]
+use once_cell::sync::Lazy;
+#[cfg(feature = "metrics")]
+use prometheus::{
+    register_histogram, register_int_counter_vec, register_int_gauge_vec, Encoder, Histogram,
+    IntCounterVec, IntGaugeVec, TextEncoder,
+};
+#[cfg(feature = "metrics")]
+use std::{convert::Infallible, net::SocketAddr};
+
+#[cfg(feature = "metrics")]
+static HOOKS_TRIGGERED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "docbot_hooks_triggered_total",
+        "Runs of a hook triggered by a rollout",
+        &["kind", "hook"]
+    )
+    .unwrap()
+});
+
+#[cfg(feature = "metrics")]
+static JOBS_CREATED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "docbot_jobs_created_total",
+        "Jobs created for a hook",
+        &["kind", "hook"]
+    )
+    .unwrap()
+});
+
+#[cfg(feature = "metrics")]
+static JOB_CREATION_FAILURES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "docbot_job_creation_failures_total",
+        "Failed attempts at creating the job of a hook",
+        &["kind", "hook"]
+    )
+    .unwrap()
+});
+
+#[cfg(feature = "metrics")]
+static JOBS_FAILED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "docbot_jobs_failed_total",
+        "Jobs of a hook that failed",
+        &["kind", "hook"]
+    )
+    .unwrap()
+});
+
+#[cfg(feature = "metrics")]
+static STATUS_UPDATE_FAILURES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "docbot_status_update_failures_total",
+        "Failed updates of a hook status after its job was created",
+        &["kind", "hook"]
+    )
+    .unwrap()
+});
+
+#[cfg(feature = "metrics")]
+static WATCH_RESTARTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "docbot_watch_restarts_total",
+        "Errors a watcher recovered from by watching again or re-listing",
+        &["watcher"]
+    )
+    .unwrap()
+});
+
+#[cfg(feature = "metrics")]
+static ROLLOUT_TO_JOB_CREATION: Lazy<Histogram> = Lazy::new(|| {
+    register_histogram!(
+        "docbot_rollout_to_job_creation_seconds",
+        "Time from noticing a rollout finished to creating the job of a hook for it",
+        vec![0.1, 0.5, 1.0, 5.0, 15.0, 30.0, 60.0, 300.0, 900.0]
+    )
+    .unwrap()
+});
+
+#[cfg(feature = "metrics")]
+static CACHE_SIZE: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "docbot_cache_size",
+        "Number of entries in a cache of the controller",
+        &["cache"]
+    )
+    .unwrap()
+});
+
+pub fn hook_triggered(kind: &str, hook: &str) {
+    #[cfg(feature = "metrics")]
+    HOOKS_TRIGGERED.with_label_values(&[kind, hook]).inc();
+    #[cfg(not(feature = "metrics"))]
+    let _ = (kind, hook);
+}
+
+/// Count a created job, `triggered_at` being when the rollout that triggered it was noticed.
+pub fn job_created(kind: &str, hook: &str, triggered_at: DateTime<Utc>) {
+    #[cfg(feature = "metrics")]
+    {
+        JOBS_CREATED.with_label_values(&[kind, hook]).inc();
+        let elapsed = (Utc::now() - triggered_at).to_std().unwrap_or_default();
+        ROLLOUT_TO_JOB_CREATION.observe(elapsed.as_secs_f64());
+    }
+    #[cfg(not(feature = "metrics"))]
+    let _ = (kind, hook, triggered_at);
+}
+
+pub fn job_creation_failed(kind: &str, hook: &str) {
+    #[cfg(feature = "metrics")]
+    JOB_CREATION_FAILURES.with_label_values(&[kind, hook]).inc();
+    #[cfg(not(feature = "metrics"))]
+    let _ = (kind, hook);
+}
+
+pub fn job_failed(kind: &str, hook: &str) {
+    #[cfg(feature = "metrics")]
+    JOBS_FAILED.with_label_values(&[kind, hook]).inc();
+    #[cfg(not(feature = "metrics"))]
+    let _ = (kind, hook);
+}
+
+pub fn status_update_failed(kind: &str, hook: &str) {
+    #[cfg(feature = "metrics")]
+    STATUS_UPDATE_FAILURES
+        .with_label_values(&[kind, hook])
+        .inc();
+    #[cfg(not(feature = "metrics"))]
+    let _ = (kind, hook);
+}
+
+pub fn watch_restarted(watcher: &str) {
+    #[cfg(feature = "metrics")]
+    WATCH_RESTARTS.with_label_values(&[watcher]).inc();
+    #[cfg(not(feature = "metrics"))]
+    let _ = watcher;
+}
+
+/// The cache sizes are read when scraped rather than tracked on every change.
+#[cfg(feature = "metrics")]
+fn record_cache_sizes(ctx: &Context) {
+    for (cache, size) in [
+        ("deployment_hooks", ctx.hook_cache.len()),
+        ("cluster_deployment_hooks", ctx.cluster_hook_cache.len()),
+        ("deployment_pod_template_hashes", ctx.deployment_cache.len()),
+        (
+            "failed_deployment_pod_template_hashes",
+            ctx.failed_deployment_cache.len(),
+        ),
+    ] {
+        CACHE_SIZE.with_label_values(&[cache]).set(size as i64);
+    }
+}
+
+#[cfg(feature = "metrics")]
+fn respond(ctx: &Context, request: &Request<Body>) -> Response<Body> {
+    if request.method() != Method::GET || request.uri().path() != "/metrics" {
+        return Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty())
+            .unwrap();
+    }
+
+    record_cache_sizes(ctx);
+
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    match encoder.encode(&prometheus::gather(), &mut buffer) {
+        Ok(()) => Response::builder()
+            .header(hyper::header::CONTENT_TYPE, encoder.format_type())
+            .body(Body::from(buffer))
+            .unwrap(),
+        Err(err) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(Body::from(err.to_string()))
+            .unwrap(),
+    }
+}
+
+/// Serve the metrics on `/metrics` of `addr` until the server fails.
+#[cfg(feature = "metrics")]
+pub async fn serve(ctx: Context, addr: SocketAddr) -> Result<(), hyper::Error> {
+    let make_service = make_service_fn(move |_| {
+        let ctx = ctx.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let response = respond(&ctx, &request);
+                async move { Ok::<_, Infallible>(response) }
+            }))
+        }
+    });
+
+    Server::bind(&addr).serve(make_service).await
+}
@@ -0,0 +1,188 @@
+use crate::context::Context;
+use crate::utils::WorkloadExt;
+use crate::{handle_deployment, retry, watch, ResourceFormatter};
+use docbot_crd::{TargetKind, HOOKS_LABEL};
+use futures::StreamExt;
+use kube::{api::ListParams, Api};
+use kube_runtime::{
+    applier,
+    controller::{self, trigger_self, Action},
+    reflector::{reflector, store::Writer},
+    utils::{try_flatten_applied, CancelableJoinHandle, StreamBackoff},
+    watcher::{self, watcher},
+};
+use std::collections::BTreeMap;
+use std::fmt::{self, Debug};
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::runtime::Handle;
+use tracing::info;
+
+/// How often a follower checks whether the leader handled a finished rollout.
+const FOLLOWER_REQUEUE: Duration = Duration::from_secs(5);
+
+/// State shared by the reconciles of one kind of workload.
+struct WorkloadReconciler {
+    ctx: Context,
+    target_kind: TargetKind,
+    /// Consecutive failed reconciles of every workload, by namespace and name.
+    failures: Mutex<BTreeMap<String, u32>>,
+    /// Rollouts a follower saw finish but hasn't recorded yet, the pod template hash and when
+    /// it was first seen by namespace and name.
+    finished: Mutex<BTreeMap<String, (String, Instant)>>,
+}
+
+/// A failed reconcile of `workload`, requeued by `error_policy`.
+#[derive(Debug)]
+struct ReconcileError {
+    workload: String,
+    message: String,
+}
+
+impl fmt::Display for ReconcileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for ReconcileError {}
+
+async fn reconcile<W: WorkloadExt>(
+    workload: Arc<W>,
+    reconciler: controller::Context<WorkloadReconciler>,
+) -> Result<Action, ReconcileError> {
+    let reconciler = reconciler.get_ref();
+    let name = workload.meta().formatted_name();
+
+    if !reconciler.ctx.leadership.is_leader() {
+        return Ok(record_finished_rollout(reconciler, workload.as_ref()));
+    }
+    reconciler.finished.lock().unwrap().remove(&name);
+
+    // Errors are turned into strings right away, the boxed error isn't Send.
+    let result = handle_deployment(&reconciler.ctx, workload.as_ref().clone())
+        .await
+        .map_err(|err| err.to_string());
+
+    match result {
+        Ok(()) => {
+            reconciler.failures.lock().unwrap().remove(&name);
+            Ok(Action::await_change())
+        }
+        Err(message) => Err(ReconcileError {
+            workload: name,
+            message,
+        }),
+    }
+}
+
+/// On a follower, record a finished rollout once the leader renewed its lease after the
+/// rollout finished, so the leader had the chance to run its hooks and a takeover doesn't run
+/// them again. Until then the rollout is requeued, and handled like any other once we lead.
+fn record_finished_rollout<W: WorkloadExt>(
+    reconciler: &WorkloadReconciler,
+    workload: &W,
+) -> Action {
+    let name = workload.meta().formatted_name();
+    let succeeded = workload.did_successfully_deploy();
+    let mut finished = reconciler.finished.lock().unwrap();
+    let hash = match workload.pod_template_hash() {
+        Some(hash) if succeeded || workload.did_fail_to_deploy() => hash,
+        _ => {
+            finished.remove(&name);
+            return Action::await_change();
+        }
+    };
+
+    let seen = match finished.get(&name) {
+        Some((finished_hash, seen)) if *finished_hash == hash => *seen,
+        _ => {
+            let now = Instant::now();
+            finished.insert(name.clone(), (hash, now));
+            now
+        }
+    };
+    if !reconciler.ctx.leadership.renewed_since(seen) {
+        return Action::requeue(FOLLOWER_REQUEUE);
+    }
+
+    finished.remove(&name);
+    let ctx = &reconciler.ctx;
+    if succeeded {
+        ctx.deployment_cache.update_cache(workload);
+        ctx.deployment_cache.is_new_generation(workload);
+    } else {
+        ctx.failed_deployment_cache.update_cache(workload);
+    }
+    Action::await_change()
+}
+
+/// Requeue the workload of a failed reconcile, backing off further with every consecutive
+/// failure of that workload.
+fn error_policy(
+    error: &ReconcileError,
+    reconciler: controller::Context<WorkloadReconciler>,
+) -> Action {
+    let reconciler = reconciler.get_ref();
+    let mut failures = reconciler.failures.lock().unwrap();
+    let attempts = failures.entry(error.workload.clone()).or_default();
+    *attempts += 1;
+
+    info!(
+        "Failed to handle {} {} (attempt {}), error: {}",
+        reconciler.target_kind.kind(),
+        error.workload,
+        attempts,
+        error.message
+    );
+
+    Action::requeue(retry::backoff(*attempts))
+}
+
+/// Reconcile the workloads of `target_kind` that opted in to hooks whenever they change. The
+/// watch lists again when it falls out of the api server's window, and backs off when it fails.
+pub async fn run_workload_controller<W: WorkloadExt>(
+    ctx: Context,
+    target_kind: TargetKind,
+    api: Api<W>,
+    dyntype: W::DynamicType,
+) where
+    W::DynamicType: Clone + Debug + Eq + Hash + Unpin,
+{
+    let reconciler = controller::Context::new(WorkloadReconciler {
+        ctx: ctx.clone(),
+        target_kind,
+        failures: Mutex::default(),
+        finished: Mutex::default(),
+    });
+
+    info!("{} controller starting...", target_kind.kind());
+
+    let writer = Writer::new(dyntype.clone());
+    let store = writer.as_reader();
+    let events = watch::observed(
+        StreamBackoff::new(
+            reflector(
+                writer,
+                watcher(api, ListParams::default().labels(HOOKS_LABEL)),
+            ),
+            watcher::default_backoff(),
+        ),
+        ctx.health.clone(),
+        target_kind.kind().to_string(),
+    );
+
+    // Watch errors are reported by `observed`, failed reconciles by `error_policy`.
+    applier(
+        |workload, reconciler| {
+            CancelableJoinHandle::spawn(reconcile(workload, reconciler), &Handle::current())
+        },
+        error_policy,
+        reconciler,
+        store,
+        trigger_self(try_flatten_applied(events), dyntype),
+    )
+    .for_each(|_| futures::future::ready(()))
+    .await;
+}
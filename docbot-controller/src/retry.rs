@@ -0,0 +1,352 @@
+use crate::context::Context;
+use crate::events::EventType;
+use crate::metrics;
+use crate::status::update_hook_status;
+use crate::utils::WorkloadExt;
+use crate::{create_job_for_deployment_hook, ResourceFormatter};
+use docbot_crd::{rollout_api_resource, Hook, JobCreationRetry, TargetKind};
+use k8s_openapi::api::apps::v1::{DaemonSet, Deployment, StatefulSet};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
+use k8s_openapi::chrono::{self, DateTime, Utc};
+use kube::{core::DynamicObject, Api};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use tracing::info;
+
+/// Number of failed attempts after which a run of a hook is given up on.
+const MAX_ATTEMPTS: u32 = 6;
+const INITIAL_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+/// Retries that may talk to the api server at the same time, so a burst of failures caused by
+/// throttling doesn't make the throttling worse.
+const MAX_CONCURRENT_RETRIES: usize = 4;
+
+/// Rate limits the retries of failed job creations. Clones share the same limit.
+#[derive(Clone)]
+pub struct RetryQueue {
+    permits: Arc<Semaphore>,
+}
+
+impl Default for RetryQueue {
+    fn default() -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(MAX_CONCURRENT_RETRIES)),
+        }
+    }
+}
+
+/// The delay before the next attempt, doubling with every failed attempt.
+pub fn backoff(attempts: u32) -> Duration {
+    INITIAL_BACKOFF
+        .saturating_mul(2u32.saturating_pow(attempts.saturating_sub(1)))
+        .min(MAX_BACKOFF)
+}
+
+/// Create the job for a run of the hook, retrying with an exponential backoff when that fails.
+/// Every failed attempt is recorded in the hook status, which is also how a retry notices it
+/// was superseded by a newer run of the hook. `attempts` is non zero for runs resumed after a
+/// restart, `triggered_at` is when the run was first triggered.
+pub async fn create_job_with_retries<H: Hook, W: WorkloadExt>(
+    ctx: &Context,
+    hook: &H,
+    deployment: &W,
+    mut attempts: u32,
+    triggered_at: DateTime<Utc>,
+) {
+    loop {
+        // Only the retries are rate limited, first attempts go out as rollouts happen.
+        let permit = match attempts {
+            0 => None,
+            _ => Some(ctx.retry_queue.permits.acquire().await),
+        };
+        let result = create_job_for_deployment_hook(ctx, hook, deployment, triggered_at)
+            .await
+            .map_err(|err| err.to_string());
+        drop(permit);
+
+        let error = match result {
+            Ok(()) => return,
+            Err(error) => error,
+        };
+
+        metrics::job_creation_failed(H::kind(&()).as_ref(), &hook.meta().formatted_name());
+        attempts += 1;
+        let exhausted = attempts >= MAX_ATTEMPTS;
+        let delay = backoff(attempts);
+
+        info!(
+            "Failed to create a job for {} {} generated by {} {} (attempt {}/{}), error: {}",
+            H::kind(&()),
+            hook.meta().formatted_name(),
+            deployment.target_kind().kind(),
+            deployment.meta().formatted_name(),
+            attempts,
+            MAX_ATTEMPTS,
+            error
+        );
+
+        let retry = JobCreationRetry {
+            deployment_kind: deployment.target_kind(),
+            deployment: deployment.meta().formatted_name(),
+            pod_template_hash: deployment.pod_template_hash(),
+            attempts,
+            last_error: error.clone(),
+            last_attempt_time: Some(Time(Utc::now())),
+            next_attempt_time: (!exhausted)
+                .then(|| Time(Utc::now() + chrono::Duration::seconds(delay.as_secs() as i64))),
+        };
+
+        let recorded = update_hook_status::<H, _>(
+            &ctx.client,
+            hook.meta().namespace.as_deref(),
+            hook.meta().name.as_deref().unwrap_or_default(),
+            |status| status.record_retry(retry.clone()),
+        )
+        .await
+        .map_err(|err| {
+            info!(
+                "Failed to record the retry of {} {}, error: {:?}",
+                H::kind(&()),
+                hook.meta().formatted_name(),
+                err
+            )
+        })
+        .is_ok();
+
+        if exhausted {
+            ctx.recorder
+                .publish(
+                    hook.object_ref(&()),
+                    EventType::Warning,
+                    "RetriesExhausted",
+                    format!(
+                        "Gave up creating a job for {} {} after {} attempts: {}",
+                        deployment.target_kind().kind(),
+                        deployment.meta().formatted_name(),
+                        attempts,
+                        error
+                    ),
+                )
+                .await;
+            return;
+        }
+
+        tokio::time::sleep(delay).await;
+
+        // Without the status we can't tell whether a newer run superseded this one.
+        if recorded && !is_pending(ctx, hook, &retry).await {
+            info!(
+                "Dropping the retry of {} {}, a newer run superseded it",
+                H::kind(&()),
+                hook.meta().formatted_name()
+            );
+            return;
+        }
+    }
+}
+
+/// Whether the retry recorded in the hook status for the deployment is still the one we
+/// recorded last.
+async fn is_pending<H: Hook>(ctx: &Context, hook: &H, retry: &JobCreationRetry) -> bool {
+    let hooks_api: Api<H> = match hook.meta().namespace.as_deref() {
+        Some(namespace) => Api::namespaced(ctx.client.clone(), namespace),
+        None => Api::all(ctx.client.clone()),
+    };
+
+    match hooks_api
+        .get_status(hook.meta().name.as_deref().unwrap_or_default())
+        .await
+    {
+        Ok(hook) => {
+            hook.hook_status()
+                .and_then(|status| status.retry_for(retry.deployment_kind, &retry.deployment))
+                == Some(retry)
+        }
+        // The hook is gone
+        Err(_) => false,
+    }
+}
+
+/// Pick up the retries that were still pending when the controller last stopped.
+pub fn resume(ctx: &Context) {
+    for hook in ctx.hook_cache.all() {
+        resume_hook(ctx, hook);
+    }
+    for hook in ctx.cluster_hook_cache.all() {
+        resume_hook(ctx, hook);
+    }
+}
+
+fn resume_hook<H: Hook>(ctx: &Context, hook: H) {
+    let retries = hook
+        .hook_status()
+        .map(|status| status.retries.clone())
+        .unwrap_or_default();
+
+    for retry in retries {
+        if retry.next_attempt_time.is_none() {
+            continue;
+        }
+
+        tokio::spawn({
+            let ctx = ctx.clone();
+            let hook = hook.clone();
+
+            async move {
+                if let Err(err) = resume_retry(&ctx, &hook, &retry).await {
+                    info!(
+                        "Failed to resume the retry of {} {} for {}, error: {:?}",
+                        H::kind(&()),
+                        hook.meta().formatted_name(),
+                        retry.deployment,
+                        err
+                    );
+                }
+            }
+        });
+    }
+}
+
+async fn resume_retry<H: Hook>(
+    ctx: &Context,
+    hook: &H,
+    retry: &JobCreationRetry,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let (namespace, name) = retry
+        .deployment
+        .split_once('/')
+        .ok_or("Malformed deployment reference")?;
+
+    let client = ctx.client.clone();
+    match retry.deployment_kind {
+        TargetKind::Deployment => {
+            let api: Api<Deployment> = Api::namespaced(client, namespace);
+            resume_for_workload(ctx, hook, retry, api.get(name).await?).await
+        }
+        TargetKind::StatefulSet => {
+            let api: Api<StatefulSet> = Api::namespaced(client, namespace);
+            resume_for_workload(ctx, hook, retry, api.get(name).await?).await
+        }
+        TargetKind::DaemonSet => {
+            let api: Api<DaemonSet> = Api::namespaced(client, namespace);
+            resume_for_workload(ctx, hook, retry, api.get(name).await?).await
+        }
+        TargetKind::Rollout => {
+            let api: Api<DynamicObject> =
+                Api::namespaced_with(client, namespace, &rollout_api_resource());
+            resume_for_workload(ctx, hook, retry, api.get(name).await?).await
+        }
+    }
+
+    Ok(())
+}
+
+async fn resume_for_workload<H: Hook, W: WorkloadExt>(
+    ctx: &Context,
+    hook: &H,
+    retry: &JobCreationRetry,
+    deployment: W,
+) {
+    // The deployment rolled out another pod template while we were down.
+    if deployment.pod_template_hash() != retry.pod_template_hash {
+        return;
+    }
+
+    if let Some(Time(next_attempt_time)) = retry.next_attempt_time {
+        let delay = (next_attempt_time - Utc::now())
+            .to_std()
+            .unwrap_or_default();
+        tokio::time::sleep(delay).await;
+    }
+
+    // The job may have been created after all, e.g. when the retry that succeeded couldn't clear
+    // itself from the status.
+    if job_created(ctx, hook, &deployment).await {
+        info!(
+            "Dropping the retry of {} {}, its job was already created for {} {}",
+            H::kind(&()),
+            hook.meta().formatted_name(),
+            deployment.target_kind().kind(),
+            deployment.meta().formatted_name()
+        );
+        let cleared = update_hook_status::<H, _>(
+            &ctx.client,
+            hook.meta().namespace.as_deref(),
+            hook.meta().name.as_deref().unwrap_or_default(),
+            |status| status.clear_retry(retry.deployment_kind, &retry.deployment),
+        )
+        .await;
+        if let Err(err) = cleared {
+            info!(
+                "Failed to clear the retry of {} {}, error: {:?}",
+                H::kind(&()),
+                hook.meta().formatted_name(),
+                err
+            );
+        }
+        return;
+    }
+
+    info!(
+        "Resuming the retry of {} {} for {} {}",
+        H::kind(&()),
+        hook.meta().formatted_name(),
+        deployment.target_kind().kind(),
+        deployment.meta().formatted_name()
+    );
+    // The run was claimed when the rollout was noticed, the time spent before the restart
+    // counts too.
+    let triggered_at = hook
+        .hook_status()
+        .and_then(|status| {
+            status.run_for(
+                deployment.target_kind(),
+                &deployment.meta().formatted_name(),
+                retry.pod_template_hash.as_deref().unwrap_or_default(),
+            )
+        })
+        .and_then(|run| run.triggered_time.clone())
+        .map_or_else(Utc::now, |Time(time)| time);
+    create_job_with_retries(ctx, hook, &deployment, retry.attempts, triggered_at).await;
+}
+
+/// Whether the status of the hook records a job created for this rollout of the deployment.
+async fn job_created<H: Hook>(ctx: &Context, hook: &H, deployment: &impl WorkloadExt) -> bool {
+    let hooks_api: Api<H> = match hook.meta().namespace.as_deref() {
+        Some(namespace) => Api::namespaced(ctx.client.clone(), namespace),
+        None => Api::all(ctx.client.clone()),
+    };
+    let hash = deployment.pod_template_hash().unwrap_or_default();
+
+    match hooks_api
+        .get_status(hook.meta().name.as_deref().unwrap_or_default())
+        .await
+    {
+        Ok(hook) => hook
+            .hook_status()
+            .and_then(|status| {
+                status.run_for(
+                    deployment.target_kind(),
+                    &deployment.meta().formatted_name(),
+                    &hash,
+                )
+            })
+            .is_some_and(|run| run.job_name.is_some()),
+        // Creating the job tells whether the hook is gone.
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_the_limit() {
+        let delays: Vec<u64> = (1..=10)
+            .map(|attempts| backoff(attempts).as_secs())
+            .collect();
+        assert_eq!(delays, vec![5, 10, 20, 40, 80, 160, 300, 300, 300, 300]);
+    }
+}
@@ -0,0 +1,249 @@
+use crate::context::Context;
+use crate::events::EventType;
+use crate::job::triggering_deployment;
+use crate::status::update_hook_status;
+use crate::utils::{JobExt, WorkloadExt};
+use crate::ResourceFormatter;
+use docbot_crd::{
+    DeploymentHook, FailurePolicy, HookCondition, HookPhase, RolloutPhase, TargetKind,
+    CONDITION_ROLLED_BACK,
+};
+use k8s_openapi::api::apps::v1::{Deployment, ReplicaSet};
+use k8s_openapi::api::batch::v1::Job;
+use k8s_openapi::api::core::v1::PodTemplateSpec;
+use kube::{
+    api::{ListParams, PostParams},
+    Api,
+};
+use std::collections::BTreeMap;
+use tracing::info;
+
+const REVISION_ANNOTATION: &str = "deployment.kubernetes.io/revision";
+
+fn revision(annotations: &Option<BTreeMap<String, String>>) -> Option<i64> {
+    annotations
+        .as_ref()
+        .and_then(|annotations| annotations.get(REVISION_ANNOTATION))
+        .and_then(|revision| revision.parse().ok())
+}
+
+/// The revision and pod template of the replica set the deployment rolled out before its
+/// current revision, the same one `kubectl rollout undo` goes back to.
+fn previous_template(
+    deployment: &Deployment,
+    replica_sets: &[ReplicaSet],
+) -> Option<(i64, PodTemplateSpec)> {
+    let current = revision(&deployment.metadata.annotations)?;
+
+    replica_sets
+        .iter()
+        .filter(|replica_set| {
+            replica_set
+                .metadata
+                .owner_references
+                .iter()
+                .flatten()
+                .any(|owner| Some(&owner.uid) == deployment.metadata.uid.as_ref())
+        })
+        .filter_map(|replica_set| {
+            let revision = revision(&replica_set.metadata.annotations)?;
+            let template = replica_set.spec.as_ref()?.template.clone()?;
+            Some((revision, template))
+        })
+        .filter(|(revision, _)| *revision < current)
+        .max_by_key(|(revision, _)| *revision)
+        .map(|(revision, mut template)| {
+            // The replica set adds this label to its pods, the deployment template doesn't have it.
+            if let Some(ref mut labels) = template
+                .metadata
+                .as_mut()
+                .and_then(|metadata| metadata.labels.as_mut())
+            {
+                labels.remove("pod-template-hash");
+            }
+            (revision, template)
+        })
+}
+
+/// Roll the deployment that triggered a failed job back to its previous revision, when the
+/// hook owning the job asks for it.
+pub async fn record_job_progress(
+    ctx: &Context,
+    job: &Job,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if job.hook_phase() != HookPhase::Failed {
+        return Ok(());
+    }
+
+    let triggered_by = match triggering_deployment(job) {
+        Some(deployment) if deployment.kind == TargetKind::Deployment => deployment,
+        _ => return Ok(()),
+    };
+
+    let hook = job
+        .metadata
+        .owner_references
+        .iter()
+        .flatten()
+        .find(|owner| owner.kind == "DeploymentHook")
+        .and_then(|owner| {
+            ctx.hook_cache.get(
+                job.metadata.namespace.as_deref().unwrap_or_default(),
+                &owner.name,
+            )
+        });
+    let hook: DeploymentHook = match hook {
+        Some(hook)
+            if hook.spec.on_failure == FailurePolicy::Rollback
+                && hook.spec.phase == RolloutPhase::PostRollout =>
+        {
+            hook
+        }
+        _ => return Ok(()),
+    };
+
+    let deployment_api: Api<Deployment> =
+        Api::namespaced(ctx.client.clone(), &triggered_by.namespace);
+    let replica_set_api: Api<ReplicaSet> =
+        Api::namespaced(ctx.client.clone(), &triggered_by.namespace);
+
+    // The failing rollout keeps updating the deployment, a conflict means it changed since we
+    // read it and the rollback is worked out again from the fresh copy.
+    let (deployment, revision) = loop {
+        let mut deployment = deployment_api.get(&triggered_by.name).await?;
+
+        // The deployment already moved on, possibly because we rolled it back before.
+        if deployment.pod_template_hash() != Some(triggered_by.pod_template_hash.clone()) {
+            return Ok(());
+        }
+
+        let replica_sets = replica_set_api.list(&ListParams::default()).await?;
+        let (revision, template) = match previous_template(&deployment, &replica_sets.items) {
+            Some(previous) => previous,
+            None => {
+                info!(
+                    "Hook {} failed but deployment {} has no previous revision to roll back to",
+                    hook.metadata.formatted_name(),
+                    deployment.metadata.formatted_name()
+                );
+                return Ok(());
+            }
+        };
+
+        if let Some(ref mut spec) = deployment.spec {
+            spec.template = template;
+        }
+
+        match deployment_api
+            .replace(&triggered_by.name, &PostParams::default(), &deployment)
+            .await
+        {
+            Ok(_) => break (deployment, revision),
+            Err(kube::Error::Api(err)) if err.code == 409 => continue,
+            Err(err) => return Err(err.into()),
+        }
+    };
+
+    // The previous pod template rolling out again must not trigger the hooks a second time.
+    ctx.deployment_cache.update_cache(&deployment);
+
+    let message = format!(
+        "Job {} of hook {} failed, rolled deployment {} back to revision {}",
+        job.metadata.name.as_deref().unwrap_or_default(),
+        hook.metadata.formatted_name(),
+        deployment.metadata.formatted_name(),
+        revision
+    );
+    info!("{}", message);
+
+    ctx.recorder
+        .publish(
+            deployment.object_reference(),
+            EventType::Warning,
+            "RolledBack",
+            message.clone(),
+        )
+        .await;
+
+    update_hook_status::<DeploymentHook, _>(
+        &ctx.client,
+        hook.metadata.namespace.as_deref(),
+        hook.metadata.name.as_deref().unwrap_or_default(),
+        |status| {
+            status.set_condition(HookCondition::new(
+                CONDITION_ROLLED_BACK,
+                true,
+                "JobFailed",
+                message.clone(),
+            ))
+        },
+    )
+    .await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn replica_set(name: &str, owner_uid: &str, revision: &str, image: &str) -> ReplicaSet {
+        serde_yaml::from_str(&format!(
+            r#"
+metadata:
+  name: {name}
+  annotations:
+    deployment.kubernetes.io/revision: "{revision}"
+  ownerReferences:
+  - apiVersion: apps/v1
+    kind: Deployment
+    name: nginx-deployment
+    uid: "{owner_uid}"
+spec:
+  selector:
+    matchLabels:
+      app: nginx
+  template:
+    metadata:
+      labels:
+        app: nginx
+        pod-template-hash: {name}
+    spec:
+      containers:
+      - name: nginx
+        image: {image}
+"#
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn rolling_back_to_the_previous_revision() {
+        let deployment: Deployment = serde_yaml::from_str(
+            r#"
+metadata:
+  name: nginx-deployment
+  uid: "1234"
+  annotations:
+    deployment.kubernetes.io/revision: "7"
+"#,
+        )
+        .unwrap();
+
+        let replica_sets = vec![
+            replica_set("nginx-5", "1234", "5", "nginx:1.12"),
+            replica_set("nginx-6", "1234", "6", "nginx:1.13"),
+            replica_set("nginx-7", "1234", "7", "nginx:1.14"),
+            replica_set("other-6", "5678", "6", "httpd:2.4"),
+        ];
+
+        let (revision, template) = previous_template(&deployment, &replica_sets).unwrap();
+        assert_eq!(revision, 6);
+        assert_eq!(
+            template.spec.unwrap().containers[0].image.as_deref(),
+            Some("nginx:1.13")
+        );
+        assert_eq!(
+            template.metadata.unwrap().labels.unwrap(),
+            [("app".to_string(), "nginx".to_string())].into()
+        );
+    }
+}
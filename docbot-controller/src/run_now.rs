@@ -0,0 +1,193 @@
+use crate::context::Context;
+use crate::events::EventType;
+use crate::status::update_hook_status;
+use crate::utils::WorkloadExt;
+use crate::{spawn_job_for_hook, ResourceFormatter};
+use docbot_crd::{rollout_api_resource, Hook, TargetKind};
+use k8s_openapi::api::apps::v1::{DaemonSet, Deployment, StatefulSet};
+use kube::{api::ListParams, client::Client, core::DynamicObject, Api, Resource};
+use tracing::info;
+
+/// Run the hook when its run-now annotation carries a token we haven't handled yet. The token
+/// is recorded in the status before the job is created, so a token never runs a hook twice.
+pub async fn handle_hook<H: Hook>(
+    ctx: &Context,
+    hook: &H,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let token = match hook.run_now_token() {
+        Some(token) => token.to_string(),
+        None => return Ok(()),
+    };
+
+    // The status of the watched object may be older than our last update of it.
+    let hooks_api: Api<H> = match hook.meta().namespace.as_deref() {
+        Some(namespace) => Api::namespaced(ctx.client.clone(), namespace),
+        None => Api::all(ctx.client.clone()),
+    };
+    let name = hook.meta().name.as_deref().unwrap_or_default();
+    let hook = hooks_api.get_status(name).await?;
+    let status = hook.hook_status().cloned().unwrap_or_default();
+    if status.handled_run_now_token.as_deref() == Some(token.as_str()) {
+        return Ok(());
+    }
+
+    update_hook_status::<H, _>(
+        &ctx.client,
+        hook.meta().namespace.as_deref(),
+        name,
+        |status| status.handled_run_now_token = Some(token.clone()),
+    )
+    .await?;
+
+    // The token is handled all the same, resuming the hook must not run it out of the blue.
+    if hook.suspended() {
+        let message = format!("Not running the hook for run-now token {token}, it is suspended");
+        info!(
+            "{} {}: {}",
+            H::kind(&()),
+            hook.meta().formatted_name(),
+            message
+        );
+        ctx.recorder
+            .publish(
+                hook.object_ref(&()),
+                EventType::Warning,
+                "RunNowSuspended",
+                message,
+            )
+            .await;
+        return Ok(());
+    }
+
+    info!(
+        "{} {} was asked to run now with token {}",
+        H::kind(&()),
+        hook.meta().formatted_name(),
+        token
+    );
+
+    // Only the workloads that opted in to hooks and match the hook's selector, in the hook's
+    // namespace when it can't match any other.
+    let client = ctx.client.clone();
+    let last_deployment = status.last_deployment.as_deref();
+    let namespace = hook.deployment_namespace();
+    let list_params = ListParams::default().labels(&hook.deployment_selector().list_selector());
+    match hook.target_kind() {
+        TargetKind::Deployment => {
+            let api: Api<Deployment> = workload_api(client, namespace);
+            run_for_latest(ctx, hook, last_deployment, api, &list_params).await
+        }
+        TargetKind::StatefulSet => {
+            let api: Api<StatefulSet> = workload_api(client, namespace);
+            run_for_latest(ctx, hook, last_deployment, api, &list_params).await
+        }
+        TargetKind::DaemonSet => {
+            let api: Api<DaemonSet> = workload_api(client, namespace);
+            run_for_latest(ctx, hook, last_deployment, api, &list_params).await
+        }
+        TargetKind::Rollout => {
+            let api: Api<DynamicObject> = match namespace {
+                Some(namespace) => Api::namespaced_with(client, namespace, &rollout_api_resource()),
+                None => Api::all_with(client, &rollout_api_resource()),
+            };
+            run_for_latest(ctx, hook, last_deployment, api, &list_params).await
+        }
+    }
+}
+
+fn workload_api<W: Resource<DynamicType = ()>>(client: Client, namespace: Option<&str>) -> Api<W> {
+    match namespace {
+        Some(namespace) => Api::namespaced(client, namespace),
+        None => Api::all(client),
+    }
+}
+
+/// The deployment a manual run targets: the one the hook last ran for when it still matches,
+/// the most recently created matching one otherwise.
+fn latest_deployment<W: WorkloadExt>(
+    deployments: Vec<W>,
+    last_deployment: Option<&str>,
+) -> Option<W> {
+    let last = deployments.iter().position(|deployment| {
+        Some(deployment.meta().formatted_name().as_str()) == last_deployment
+    });
+
+    match last {
+        Some(index) => deployments.into_iter().nth(index),
+        None => deployments
+            .into_iter()
+            .max_by_key(|deployment| deployment.meta().creation_timestamp.clone()),
+    }
+}
+
+async fn run_for_latest<H: Hook, W: WorkloadExt>(
+    ctx: &Context,
+    hook: H,
+    last_deployment: Option<&str>,
+    api: Api<W>,
+    list_params: &ListParams,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut matching = Vec::new();
+    for deployment in api.list(list_params).await?.items {
+        let namespace = deployment.meta().namespace.as_deref().unwrap_or("default");
+        let namespace_labels = ctx.namespace_cache.get(&ctx.client, namespace).await?;
+        if hook.does_match_deployment(&deployment, &namespace_labels) {
+            matching.push(deployment);
+        }
+    }
+
+    match latest_deployment(matching, last_deployment) {
+        Some(deployment) => spawn_job_for_hook(ctx, hook, &deployment),
+        None => info!(
+            "{} {} does not match any {}, not running it",
+            H::kind(&()),
+            hook.meta().formatted_name(),
+            hook.target_kind().kind()
+        ),
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn deployment(name: &str, created: &str) -> Deployment {
+        serde_yaml::from_str(&format!(
+            r#"
+metadata:
+  name: {name}
+  namespace: docbot-test
+  creationTimestamp: "{created}"
+"#
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn runs_against_the_last_deployment_or_the_newest_one() {
+        let deployments = || {
+            vec![
+                deployment("api", "2023-01-01T00:00:00Z"),
+                deployment("worker", "2023-03-01T00:00:00Z"),
+                deployment("web", "2023-02-01T00:00:00Z"),
+            ]
+        };
+
+        let name = |deployment: Option<Deployment>| deployment.unwrap().metadata.name.unwrap();
+        assert_eq!(
+            name(latest_deployment(deployments(), Some("docbot-test/api"))),
+            "api"
+        );
+        assert_eq!(name(latest_deployment(deployments(), None)), "worker");
+        assert_eq!(
+            name(latest_deployment(
+                deployments(),
+                Some("docbot-test/deleted")
+            )),
+            "worker"
+        );
+        assert!(latest_deployment(Vec::<Deployment>::new(), None).is_none());
+    }
+}
@@ -0,0 +1,385 @@
+use crate::events::{EventType, Recorder};
+use crate::utils::{JobExt, WorkloadExt};
+use crate::ResourceFormatter;
+use docbot_crd::{
+    ClusterDeploymentHook, DeploymentHook, DeploymentHookStatus, Hook, HookCondition, HookPhase,
+    HookRun, HookTrigger, RolloutPhase, CONDITION_INVALID_SPEC, CONDITION_PROGRESSING,
+    CONDITION_SUCCEEDED,
+};
+use k8s_openapi::api::batch::v1::Job;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{OwnerReference, Time};
+use k8s_openapi::chrono::Utc;
+use kube::{
+    api::{Patch, PatchParams},
+    client::Client,
+    Api,
+};
+use serde_json::json;
+use tracing::info;
+
+/// Fetch the latest version of a hook, apply `update` to its status and write it back if
+/// anything changed. The status is written with the resource version it was read at, so a
+/// concurrent update is never clobbered with a stale copy: on a conflict the hook is read again
+/// and `update` applied to the fresh status.
+/// Cluster scoped hooks are addressed with a `None` namespace.
+pub async fn update_hook_status<H, F>(
+    client: &Client,
+    namespace: Option<&str>,
+    name: &str,
+    mut update: F,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    H: Hook,
+    F: FnMut(&mut DeploymentHookStatus),
+{
+    let hooks_api: Api<H> = match namespace {
+        Some(namespace) => Api::namespaced(client.clone(), namespace),
+        None => Api::all(client.clone()),
+    };
+
+    loop {
+        let hook = hooks_api.get_status(name).await?;
+
+        let current = hook.hook_status().cloned().unwrap_or_default();
+        let mut status = current.clone();
+        update(&mut status);
+
+        if status == current {
+            return Ok(());
+        }
+
+        let patch = json!({
+            "metadata": { "resourceVersion": hook.meta().resource_version },
+            "status": status,
+        });
+        match hooks_api
+            .patch_status(name, &PatchParams::default(), &Patch::Merge(patch))
+            .await
+        {
+            Ok(_) => return Ok(()),
+            Err(kube::Error::Api(err)) if err.code == 409 => continue,
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+/// The owner reference of a job created by docbot to the hook it was created for.
+pub fn hook_owner(job: &Job) -> Option<&OwnerReference> {
+    job.metadata
+        .owner_references
+        .iter()
+        .flatten()
+        .find(|owner| owner.kind == "DeploymentHook" || owner.kind == "ClusterDeploymentHook")
+}
+
+/// Apply `update` to the status of the hook that owns `job`, whichever kind of hook that is.
+pub async fn update_owner_status<F>(
+    client: &Client,
+    job: &Job,
+    update: F,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    F: FnMut(&mut DeploymentHookStatus),
+{
+    match (hook_owner(job), job.metadata.namespace.as_deref()) {
+        (Some(owner), _) if owner.kind == "ClusterDeploymentHook" => {
+            update_hook_status::<ClusterDeploymentHook, _>(client, None, &owner.name, update).await
+        }
+        (Some(owner), Some(namespace)) => {
+            update_hook_status::<DeploymentHook, _>(client, Some(namespace), &owner.name, update)
+                .await
+        }
+        _ => Ok(()),
+    }
+}
+
+/// What in the spec of the hook can't be honoured, if anything.
+fn spec_problem<H: Hook>(hook: &H) -> Option<String> {
+    let target_kind = hook.target_kind();
+    if hook.rollout_phase() == RolloutPhase::PostRollout
+        && hook.trigger() == HookTrigger::OnFailure
+        && !target_kind.reports_failed_rollouts()
+    {
+        return Some(format!(
+            "trigger OnFailure is not supported for {} hooks, their rollouts never fail",
+            target_kind.kind()
+        ));
+    }
+
+    None
+}
+
+/// Set the `InvalidSpec` condition of a hook whose spec can't be honoured, publishing a warning
+/// the first time, and clear it once the spec is fixed. The cached status is checked first so
+/// valid hooks don't cost a request.
+pub async fn record_spec_problem<H: Hook>(
+    client: &Client,
+    recorder: &Recorder,
+    hook: &H,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let problem = spec_problem(hook);
+    let reported = hook
+        .hook_status()
+        .and_then(|status| status.condition(CONDITION_INVALID_SPEC));
+    let up_to_date = match (&problem, reported) {
+        (None, None) => true,
+        (Some(problem), Some(reported)) => {
+            reported.is_true() && reported.message.as_ref() == Some(problem)
+        }
+        _ => false,
+    };
+    if up_to_date {
+        return Ok(());
+    }
+
+    update_hook_status::<H, _>(
+        client,
+        hook.meta().namespace.as_deref(),
+        hook.meta().name.as_deref().unwrap_or_default(),
+        |status| match problem {
+            Some(ref problem) => status.set_condition(HookCondition::new(
+                CONDITION_INVALID_SPEC,
+                true,
+                "UnsupportedTrigger",
+                problem.clone(),
+            )),
+            None => status
+                .conditions
+                .retain(|condition| condition.type_ != CONDITION_INVALID_SPEC),
+        },
+    )
+    .await?;
+
+    if let Some(problem) = problem {
+        info!(
+            "{} {} is invalid: {}",
+            H::kind(&()),
+            hook.meta().formatted_name(),
+            problem
+        );
+        recorder
+            .publish(
+                hook.object_ref(&()),
+                EventType::Warning,
+                "UnsupportedTrigger",
+                problem,
+            )
+            .await;
+    }
+
+    Ok(())
+}
+
+/// Claim the run of a hook for a rollout before creating its job, so only one of several
+/// callers racing to start it does. Returns false when the status already records a run for
+/// this rollout. The status write is guarded by the resource version, so of two racing callers
+/// the second one sees the run of the first.
+pub async fn claim_run<H: Hook>(
+    client: &Client,
+    hook: &H,
+    deployment: &impl WorkloadExt,
+    hash: &str,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let deployment_kind = deployment.target_kind();
+    let deployment_name = deployment.meta().formatted_name();
+    let mut claimed = false;
+
+    update_hook_status::<H, _>(
+        client,
+        hook.meta().namespace.as_deref(),
+        hook.meta().name.as_deref().unwrap_or_default(),
+        |status| {
+            claimed = status
+                .run_for(deployment_kind, &deployment_name, hash)
+                .is_none();
+            if !claimed {
+                return;
+            }
+
+            status.set_run(HookRun {
+                deployment_kind,
+                deployment: deployment_name.clone(),
+                pod_template_hash: Some(hash.to_string()),
+                job_name: None,
+                phase: Some(HookPhase::Pending),
+                triggered_time: Some(Time(Utc::now())),
+            });
+            status.last_deployment = Some(deployment_name.clone());
+            status.last_pod_template_hash = Some(hash.to_string());
+            status.last_job_name = None;
+            status.phase = Some(HookPhase::Pending);
+            status.last_triggered_time = Some(Time(Utc::now()));
+        },
+    )
+    .await?;
+
+    Ok(claimed)
+}
+
+/// Record that a job was just created for a hook in response to a deployment rolling out.
+pub async fn record_job_created<H: Hook>(
+    client: &Client,
+    hook: &H,
+    deployment: &impl WorkloadExt,
+    job: &Job,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let name = hook.meta().name.clone().unwrap_or_default();
+    let job_name = job.metadata.name.clone().unwrap_or_default();
+
+    update_hook_status::<H, _>(client, hook.meta().namespace.as_deref(), &name, |status| {
+        // A claimed run keeps the time it was triggered at.
+        let triggered_time = deployment
+            .pod_template_hash()
+            .and_then(|hash| {
+                status.run_for(
+                    deployment.target_kind(),
+                    &deployment.meta().formatted_name(),
+                    &hash,
+                )
+            })
+            .and_then(|run| run.triggered_time.clone())
+            .unwrap_or_else(|| Time(Utc::now()));
+        status.set_run(HookRun {
+            deployment_kind: deployment.target_kind(),
+            deployment: deployment.meta().formatted_name(),
+            pod_template_hash: deployment.pod_template_hash(),
+            job_name: Some(job_name.clone()),
+            phase: Some(HookPhase::Pending),
+            triggered_time: Some(triggered_time),
+        });
+        status.last_deployment = Some(deployment.meta().formatted_name());
+        status.last_pod_template_hash = deployment.pod_template_hash();
+        status.last_job_name = Some(job_name.clone());
+        status.phase = Some(HookPhase::Pending);
+        status.last_triggered_time = Some(Time(Utc::now()));
+        status.last_completion_time = None;
+        status.clear_retry(
+            deployment.target_kind(),
+            &deployment.meta().formatted_name(),
+        );
+        status.set_condition(HookCondition::new(
+            CONDITION_PROGRESSING,
+            true,
+            "JobCreated",
+            format!("Created job {job_name}"),
+        ));
+        status.set_condition(HookCondition::new(
+            CONDITION_SUCCEEDED,
+            false,
+            "JobCreated",
+            format!("Waiting for job {job_name} to finish"),
+        ));
+    })
+    .await
+}
+
+/// Reflect the state of a job created by docbot onto the run it was created for in the status
+/// of the hook that owns it. The rest of the status only follows the most recent job.
+pub async fn record_job_progress(
+    client: &Client,
+    job: &Job,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (owner, job_name) = match (hook_owner(job), &job.metadata.name) {
+        (Some(owner), Some(job_name)) => (owner, job_name),
+        _ => return Ok(()),
+    };
+
+    let phase = job.hook_phase();
+    let update = |status: &mut DeploymentHookStatus| {
+        if let Some(run) = status
+            .runs
+            .iter_mut()
+            .find(|run| run.job_name.as_ref() == Some(job_name))
+        {
+            run.phase = Some(phase);
+        }
+
+        if status.last_job_name.as_ref() != Some(job_name) || status.phase == Some(phase) {
+            return;
+        }
+
+        info!(
+            "Job {} for {} {} is now {:?}",
+            job.metadata.formatted_name(),
+            owner.kind,
+            owner.name,
+            phase
+        );
+
+        status.phase = Some(phase);
+        match phase {
+            HookPhase::Pending => {}
+            HookPhase::Running => {
+                status.set_condition(HookCondition::new(
+                    CONDITION_SUCCEEDED,
+                    false,
+                    "JobRunning",
+                    format!("Job {job_name} is running"),
+                ));
+            }
+            HookPhase::Succeeded | HookPhase::Failed => {
+                let succeeded = phase == HookPhase::Succeeded;
+                let reason = if succeeded {
+                    "JobSucceeded"
+                } else {
+                    "JobFailed"
+                };
+
+                status.last_completion_time = job
+                    .status
+                    .as_ref()
+                    .and_then(|job_status| job_status.completion_time.clone())
+                    .or_else(|| Some(Time(Utc::now())));
+                status.set_condition(HookCondition::new(
+                    CONDITION_PROGRESSING,
+                    false,
+                    reason,
+                    format!("Job {job_name} finished"),
+                ));
+                status.set_condition(HookCondition::new(
+                    CONDITION_SUCCEEDED,
+                    succeeded,
+                    reason,
+                    format!("Job {job_name} finished as {phase:?}"),
+                ));
+            }
+        }
+    };
+
+    update_owner_status(client, job, update).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn hook(target_kind: &str, trigger: &str) -> DeploymentHook {
+        serde_yaml::from_str(&format!(
+            r#"
+apiVersion: apps.mx.com/v1
+kind: DeploymentHook
+metadata:
+  name: rollout-diagnostics
+  namespace: docbot-test
+spec:
+  trigger: {trigger}
+  selector:
+    targetKind: {target_kind}
+    labels:
+      app: postgres
+  template:
+    name: diagnostics-template
+"#
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn failure_hooks_need_a_workload_whose_rollouts_fail() {
+        assert_eq!(spec_problem(&hook("Deployment", "OnFailure")), None);
+        assert_eq!(spec_problem(&hook("Rollout", "OnFailure")), None);
+        assert_eq!(spec_problem(&hook("StatefulSet", "OnSuccess")), None);
+        assert!(spec_problem(&hook("StatefulSet", "OnFailure")).is_some());
+        assert!(spec_problem(&hook("DaemonSet", "OnFailure")).is_some());
+    }
+}
@@ -0,0 +1,461 @@
+use crate::context::Context;
+use crate::events::EventType;
+use crate::job::triggering_deployment;
+use crate::status;
+use crate::suspend;
+use crate::utils::{JobExt, WorkloadExt};
+use crate::{spawn_job_for_hook, ResourceFormatter};
+use docbot_crd::{rollout_api_resource, DeploymentHook, Hook, HookPhase, TargetKind};
+use k8s_openapi::api::apps::v1::{DaemonSet, Deployment, StatefulSet};
+use k8s_openapi::api::batch::v1::Job;
+use kube::{client::Client, core::DynamicObject, Api};
+use tracing::info;
+
+/// Whether `other` is one of the hooks `hook` has to run after.
+fn runs_after(hook: &impl Hook, other: &impl Hook) -> bool {
+    hook.meta().namespace == other.meta().namespace
+        && other
+            .meta()
+            .name
+            .as_ref()
+            .is_some_and(|name| hook.run_after().contains(name))
+}
+
+fn same_hook(hook: &impl Hook, other: &impl Hook) -> bool {
+    hook.meta().namespace == other.meta().namespace && hook.meta().name == other.meta().name
+}
+
+/// The hooks that don't have to wait for any of the other hooks triggered by the same rollout.
+/// The rest is started by `record_job_progress` as their predecessors succeed.
+pub fn first_steps<H: Hook>(hooks: Vec<H>) -> Vec<H> {
+    hooks
+        .iter()
+        .filter(|hook| !hooks.iter().any(|other| runs_after(*hook, other)))
+        .cloned()
+        .collect()
+}
+
+/// The hooks that are neither suspended nor run after a suspended hook, directly or through
+/// other hooks. The others don't run for the rollout until the suspended hooks are resumed.
+pub fn unsuspended<H: Hook>(hooks: &[H]) -> Vec<H> {
+    let held_back: Vec<H> = hooks
+        .iter()
+        .filter(|hook| hook.suspended())
+        .flat_map(|hook| dependents(hook, hooks))
+        .collect();
+
+    hooks
+        .iter()
+        .filter(|hook| !hook.suspended() && !held_back.iter().any(|other| same_hook(other, *hook)))
+        .cloned()
+        .collect()
+}
+
+/// The hooks whose `runAfter` leads back to themselves through the other hooks.
+fn cyclic_hooks<H: Hook>(hooks: &[H]) -> Vec<H> {
+    hooks
+        .iter()
+        .filter(|hook| {
+            dependents(*hook, hooks)
+                .iter()
+                .any(|dependent| same_hook(dependent, *hook))
+        })
+        .cloned()
+        .collect()
+}
+
+/// Leave out the hooks in a `runAfter` cycle, which would wait for each other forever, along
+/// with the hooks running after them. A warning is published for every hook left out.
+pub async fn skip_cycles<H: Hook, W: WorkloadExt>(
+    ctx: &Context,
+    hooks: Vec<H>,
+    deployment: &W,
+) -> Vec<H> {
+    let cyclic = cyclic_hooks(&hooks);
+    let mut skipped = cyclic.clone();
+    for hook in &cyclic {
+        for dependent in dependents(hook, &hooks) {
+            if !skipped.iter().any(|other| same_hook(other, &dependent)) {
+                skipped.push(dependent);
+            }
+        }
+    }
+
+    for hook in &skipped {
+        let (reason, message) = if cyclic.iter().any(|other| same_hook(other, hook)) {
+            (
+                "HookCycle",
+                format!(
+                    "Skipping hook {} because its runAfter leads back to itself",
+                    hook.meta().formatted_name()
+                ),
+            )
+        } else {
+            (
+                "HookStepSkipped",
+                format!(
+                    "Skipping hook {} because it runs after a hook in a runAfter cycle",
+                    hook.meta().formatted_name()
+                ),
+            )
+        };
+        info!(
+            "{} for {} {}",
+            message,
+            deployment.target_kind().kind(),
+            deployment.meta().formatted_name()
+        );
+        ctx.recorder
+            .publish_for_hook(
+                hook.object_ref(&()),
+                Some(deployment.object_reference()),
+                EventType::Warning,
+                reason,
+                message,
+            )
+            .await;
+    }
+
+    hooks
+        .into_iter()
+        .filter(|hook| !skipped.iter().any(|other| same_hook(other, hook)))
+        .collect()
+}
+
+/// The hooks among `hooks` that run after `hook`, directly or through other hooks.
+pub fn dependents<H: Hook, D: Hook>(hook: &H, hooks: &[D]) -> Vec<D> {
+    let mut dependents: Vec<D> = hooks
+        .iter()
+        .filter(|other| runs_after(*other, hook))
+        .cloned()
+        .collect();
+
+    let mut index = 0;
+    while index < dependents.len() {
+        let next: Vec<D> = hooks
+            .iter()
+            .filter(|other| runs_after(*other, &dependents[index]))
+            .filter(|other| {
+                !dependents
+                    .iter()
+                    .any(|dependent| same_hook(dependent, *other))
+            })
+            .cloned()
+            .collect();
+        dependents.extend(next);
+        index += 1;
+    }
+
+    dependents
+}
+
+/// The phase of the run of `hook` for this rollout of the deployment, if it has one. The status
+/// is read from the api, the cache may not have caught up with it yet.
+async fn phase_for_rollout(
+    client: &Client,
+    hook: &DeploymentHook,
+    deployment: &impl WorkloadExt,
+    hash: &str,
+) -> Result<Option<HookPhase>, Box<dyn std::error::Error>> {
+    let hooks_api: Api<DeploymentHook> = Api::namespaced(
+        client.clone(),
+        hook.metadata.namespace.as_deref().unwrap_or("default"),
+    );
+    let status = hooks_api
+        .get_status(hook.metadata.name.as_deref().unwrap_or_default())
+        .await?
+        .status
+        .unwrap_or_default();
+
+    Ok(status
+        .run_for(
+            deployment.target_kind(),
+            &deployment.meta().formatted_name(),
+            hash,
+        )
+        .and_then(|run| run.phase))
+}
+
+/// Start a run of `hook` that was recorded while it was suspended. When it runs after other
+/// hooks triggered by the same rollout it only starts once all of those succeeded for it,
+/// otherwise the last of them to succeed starts it.
+pub async fn start_resumed<H: Hook, W: WorkloadExt>(
+    ctx: &Context,
+    hook: &H,
+    deployment: &W,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let hash = deployment.pod_template_hash().unwrap_or_default();
+    let namespace = deployment.meta().namespace.clone().unwrap_or_default();
+    let namespace_labels = ctx.namespace_cache.get(&ctx.client, &namespace).await?;
+    let predecessors: Vec<DeploymentHook> = ctx
+        .hook_cache
+        .find_by_matching_deployment(deployment, &namespace_labels)
+        .into_iter()
+        .filter(|other| {
+            other.rollout_phase() == hook.rollout_phase()
+                && other.trigger() == hook.trigger()
+                && runs_after(hook, other)
+        })
+        .collect();
+
+    for predecessor in &predecessors {
+        let phase = phase_for_rollout(&ctx.client, predecessor, deployment, &hash).await?;
+        if phase != Some(HookPhase::Succeeded) {
+            info!(
+                "Not replaying {} {} for {} {} yet, hook {} is {:?}",
+                H::kind(&()),
+                hook.meta().formatted_name(),
+                deployment.target_kind().kind(),
+                deployment.meta().formatted_name(),
+                predecessor.metadata.formatted_name(),
+                phase
+            );
+            return Ok(());
+        }
+    }
+
+    if status::claim_run(&ctx.client, hook, deployment, &hash).await? {
+        spawn_job_for_hook(ctx, hook.clone(), deployment);
+    }
+
+    Ok(())
+}
+
+/// Start the hooks that run after the hook owning a finished job, once all of their
+/// predecessors succeeded for the same rollout. When the job failed the hooks after it are
+/// skipped, and so are the hooks after those.
+pub async fn record_job_progress(
+    ctx: &Context,
+    job: &Job,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let phase = job.hook_phase();
+    if !phase.is_finished() {
+        return Ok(());
+    }
+
+    let triggered_by = match triggering_deployment(job) {
+        Some(deployment) => deployment,
+        None => return Ok(()),
+    };
+
+    let hook = job
+        .metadata
+        .owner_references
+        .iter()
+        .flatten()
+        .find(|owner| owner.kind == "DeploymentHook")
+        .and_then(|owner| {
+            ctx.hook_cache.get(
+                job.metadata.namespace.as_deref().unwrap_or_default(),
+                &owner.name,
+            )
+        });
+    let hook = match hook {
+        Some(hook) => hook,
+        None => return Ok(()),
+    };
+
+    let client = ctx.client.clone();
+    let namespace = &triggered_by.namespace;
+    let name = &triggered_by.name;
+    let hash = &triggered_by.pod_template_hash;
+    match triggered_by.kind {
+        TargetKind::Deployment => {
+            let api: Api<Deployment> = Api::namespaced(client, namespace);
+            start_next_steps(ctx, phase, &hook, api.get(name).await?, hash).await
+        }
+        TargetKind::StatefulSet => {
+            let api: Api<StatefulSet> = Api::namespaced(client, namespace);
+            start_next_steps(ctx, phase, &hook, api.get(name).await?, hash).await
+        }
+        TargetKind::DaemonSet => {
+            let api: Api<DaemonSet> = Api::namespaced(client, namespace);
+            start_next_steps(ctx, phase, &hook, api.get(name).await?, hash).await
+        }
+        TargetKind::Rollout => {
+            let api: Api<DynamicObject> =
+                Api::namespaced_with(client, namespace, &rollout_api_resource());
+            start_next_steps(ctx, phase, &hook, api.get(name).await?, hash).await
+        }
+    }
+}
+
+async fn start_next_steps<W: WorkloadExt>(
+    ctx: &Context,
+    phase: HookPhase,
+    hook: &DeploymentHook,
+    deployment: W,
+    hash: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // The deployment moved on to another pod template since this job was created.
+    if deployment.pod_template_hash().as_deref() != Some(hash) {
+        return Ok(());
+    }
+
+    let namespace = deployment.meta().namespace.clone().unwrap_or_default();
+    let namespace_labels = ctx.namespace_cache.get(&ctx.client, &namespace).await?;
+    let triggered: Vec<DeploymentHook> = ctx
+        .hook_cache
+        .find_by_matching_deployment(&deployment, &namespace_labels)
+        .into_iter()
+        .filter(|other| {
+            other.rollout_phase() == hook.rollout_phase() && other.trigger() == hook.trigger()
+        })
+        .collect();
+    let successors: Vec<DeploymentHook> = triggered
+        .iter()
+        .filter(|next| runs_after(*next, hook))
+        .cloned()
+        .collect();
+    let successors = if phase == HookPhase::Failed {
+        successors
+    } else {
+        suspend::skip_suspended(ctx, successors, &deployment)
+    };
+
+    for next in &successors {
+        if phase == HookPhase::Failed {
+            let message = format!(
+                "Skipping hook {} because hook {} failed",
+                next.metadata.formatted_name(),
+                hook.metadata.formatted_name()
+            );
+            info!(
+                "{} for deployment {}",
+                message,
+                deployment.meta().formatted_name()
+            );
+            ctx.recorder
+                .publish(
+                    deployment.object_reference(),
+                    EventType::Warning,
+                    "HookStepSkipped",
+                    message,
+                )
+                .await;
+            continue;
+        }
+
+        let mut ready = true;
+        for predecessor in triggered.iter().filter(|other| runs_after(next, *other)) {
+            if predecessor.metadata.name == hook.metadata.name {
+                continue;
+            }
+
+            if phase_for_rollout(&ctx.client, predecessor, &deployment, hash).await?
+                != Some(HookPhase::Succeeded)
+            {
+                ready = false;
+                break;
+            }
+        }
+
+        // Another predecessor finishing, or the same job reported twice, may race us to it.
+        if ready && status::claim_run(&ctx.client, next, &deployment, hash).await? {
+            spawn_job_for_hook(ctx, next.clone(), &deployment);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn hook(name: &str, run_after: &[&str]) -> DeploymentHook {
+        serde_yaml::from_str(&format!(
+            r#"
+apiVersion: apps.mx.com/v1
+kind: DeploymentHook
+metadata:
+  name: {name}
+  namespace: docbot-test
+spec:
+  runAfter: {run_after:?}
+  selector:
+    labels:
+      app: nginx
+  template:
+    name: nginx-template
+"#
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn only_hooks_without_triggered_predecessors_run_first() {
+        let hooks = vec![
+            hook("migrate-db", &[]),
+            hook("warm-cache", &["migrate-db"]),
+            hook("notify", &["warm-cache", "not-triggered"]),
+            hook("audit", &["not-triggered"]),
+        ];
+
+        let first: Vec<_> = first_steps(hooks)
+            .into_iter()
+            .map(|hook| hook.metadata.name.unwrap())
+            .collect();
+        assert_eq!(first, vec!["migrate-db", "audit"]);
+    }
+
+    #[test]
+    fn suspended_hooks_hold_back_the_hooks_running_after_them() {
+        let mut hooks = vec![
+            hook("migrate-db", &[]),
+            hook("warm-cache", &["migrate-db"]),
+            hook("notify", &["warm-cache"]),
+            hook("audit", &[]),
+        ];
+        hooks[0].spec.suspend = true;
+
+        let names: Vec<_> = unsuspended(&hooks)
+            .into_iter()
+            .map(|hook| hook.metadata.name.unwrap())
+            .collect();
+        assert_eq!(names, vec!["audit"]);
+
+        // The suspended hook is still the first step, so it is recorded instead of its
+        // dependents starting right away.
+        let first: Vec<_> = first_steps(hooks)
+            .into_iter()
+            .map(|hook| hook.metadata.name.unwrap())
+            .collect();
+        assert_eq!(first, vec!["migrate-db", "audit"]);
+    }
+
+    #[test]
+    fn hooks_running_after_themselves_are_cyclic() {
+        let hooks = vec![
+            hook("migrate-db", &["notify"]),
+            hook("warm-cache", &["migrate-db"]),
+            hook("notify", &["warm-cache"]),
+            hook("audit", &["audit"]),
+            hook("page", &["migrate-db"]),
+            hook("report", &[]),
+        ];
+
+        let cyclic: Vec<_> = cyclic_hooks(&hooks)
+            .into_iter()
+            .map(|hook| hook.metadata.name.unwrap())
+            .collect();
+        assert_eq!(cyclic, vec!["migrate-db", "warm-cache", "notify", "audit"]);
+    }
+
+    #[test]
+    fn dependents_include_the_hooks_running_after_them() {
+        let hooks = vec![
+            hook("migrate-db", &[]),
+            hook("warm-cache", &["migrate-db"]),
+            hook("notify", &["warm-cache"]),
+            hook("audit", &[]),
+        ];
+
+        let names: Vec<_> = dependents(&hooks[0], &hooks)
+            .into_iter()
+            .map(|hook| hook.metadata.name.unwrap())
+            .collect();
+        assert_eq!(names, vec!["warm-cache", "notify"]);
+        assert!(dependents(&hooks[3], &hooks).is_empty());
+    }
+}
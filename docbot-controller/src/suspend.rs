@@ -0,0 +1,238 @@
+use crate::context::Context;
+use crate::events::EventType;
+use crate::status::update_hook_status;
+use crate::steps;
+use crate::utils::WorkloadExt;
+use crate::ResourceFormatter;
+use docbot_crd::{
+    rollout_api_resource, DeploymentHookStatus, Hook, ResumePolicy, SuspendedRun, TargetKind,
+};
+use k8s_openapi::api::apps::v1::{DaemonSet, Deployment, StatefulSet};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
+use k8s_openapi::chrono::Utc;
+use kube::{core::DynamicObject, Api};
+use tracing::info;
+
+/// Record the rollout in the status of the suspended hooks, and return the hooks that aren't
+/// suspended.
+pub fn skip_suspended<H: Hook, W: WorkloadExt>(
+    ctx: &Context,
+    hooks: Vec<H>,
+    deployment: &W,
+) -> Vec<H> {
+    let (suspended, active): (Vec<H>, Vec<H>) = hooks.into_iter().partition(H::suspended);
+
+    for hook in suspended {
+        info!(
+            "Not running suspended {} {} for {} {}",
+            H::kind(&()),
+            hook.meta().formatted_name(),
+            deployment.target_kind().kind(),
+            deployment.meta().formatted_name()
+        );
+
+        let run = SuspendedRun {
+            deployment_kind: deployment.target_kind(),
+            deployment: deployment.meta().formatted_name(),
+            pod_template_hash: deployment.pod_template_hash(),
+            rollout_time: Some(Time(Utc::now())),
+        };
+
+        tokio::spawn({
+            let client = ctx.client.clone();
+
+            async move {
+                if let Err(err) = update_hook_status::<H, _>(
+                    &client,
+                    hook.meta().namespace.as_deref(),
+                    hook.meta().name.as_deref().unwrap_or_default(),
+                    |status| record_run(&mut status.suspended_runs, run.clone()),
+                )
+                .await
+                {
+                    info!(
+                        "Failed to record the suspended run of {} {}, error: {:?}",
+                        H::kind(&()),
+                        hook.meta().formatted_name(),
+                        err
+                    );
+                }
+            }
+        });
+    }
+
+    active
+}
+
+/// Keep only the latest rollout of every deployment, an older pod template is never replayed.
+fn record_run(runs: &mut Vec<SuspendedRun>, run: SuspendedRun) {
+    runs.retain(|other| {
+        (other.deployment_kind, &other.deployment) != (run.deployment_kind, &run.deployment)
+    });
+    runs.push(run);
+}
+
+/// Take the recorded runs out of the status. The emptied list is still written, a merge patch
+/// leaving it out would keep the runs.
+fn take_runs(status: &mut DeploymentHookStatus) -> Vec<SuspendedRun> {
+    std::mem::take(&mut status.suspended_runs)
+}
+
+/// Once a hook is unsuspended, replay or drop the runs recorded while it was suspended. The
+/// runs are cleared from the status before they are replayed so they only ever run once.
+pub async fn handle_hook<H: Hook>(
+    ctx: &Context,
+    hook: &H,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let has_runs = hook
+        .hook_status()
+        .is_some_and(|status| !status.suspended_runs.is_empty());
+    if hook.suspended() || !has_runs {
+        return Ok(());
+    }
+
+    // The runs are taken from the latest status in the same guarded write that clears them, so
+    // of two passes over the same hook only one gets them.
+    let mut runs = Vec::new();
+    update_hook_status::<H, _>(
+        &ctx.client,
+        hook.meta().namespace.as_deref(),
+        hook.meta().name.as_deref().unwrap_or_default(),
+        |status| runs = take_runs(status),
+    )
+    .await?;
+    if runs.is_empty() {
+        return Ok(());
+    }
+
+    if hook.resume_policy() == ResumePolicy::Drop {
+        let message = format!(
+            "Dropped the runs for {} rollout(s) that happened while the hook was suspended",
+            runs.len()
+        );
+        info!(
+            "{} {}: {}",
+            H::kind(&()),
+            hook.meta().formatted_name(),
+            message
+        );
+        ctx.recorder
+            .publish(
+                hook.object_ref(&()),
+                EventType::Normal,
+                "SuspendedRunsDropped",
+                message,
+            )
+            .await;
+        return Ok(());
+    }
+
+    for run in runs {
+        if let Err(err) = replay(ctx, hook, &run).await {
+            info!(
+                "Failed to replay the run of {} {} for {}, error: {:?}",
+                H::kind(&()),
+                hook.meta().formatted_name(),
+                run.deployment,
+                err
+            );
+        }
+    }
+
+    Ok(())
+}
+
+async fn replay<H: Hook>(
+    ctx: &Context,
+    hook: &H,
+    run: &SuspendedRun,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (namespace, name) = run
+        .deployment
+        .split_once('/')
+        .ok_or("Malformed deployment reference")?;
+
+    let client = ctx.client.clone();
+    match run.deployment_kind {
+        TargetKind::Deployment => {
+            let api: Api<Deployment> = Api::namespaced(client, namespace);
+            replay_for_workload(ctx, hook, run, api.get(name).await?).await
+        }
+        TargetKind::StatefulSet => {
+            let api: Api<StatefulSet> = Api::namespaced(client, namespace);
+            replay_for_workload(ctx, hook, run, api.get(name).await?).await
+        }
+        TargetKind::DaemonSet => {
+            let api: Api<DaemonSet> = Api::namespaced(client, namespace);
+            replay_for_workload(ctx, hook, run, api.get(name).await?).await
+        }
+        TargetKind::Rollout => {
+            let api: Api<DynamicObject> =
+                Api::namespaced_with(client, namespace, &rollout_api_resource());
+            replay_for_workload(ctx, hook, run, api.get(name).await?).await
+        }
+    }
+}
+
+async fn replay_for_workload<H: Hook, W: WorkloadExt>(
+    ctx: &Context,
+    hook: &H,
+    run: &SuspendedRun,
+    deployment: W,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // The deployment rolled out another pod template since, which was recorded separately or
+    // happened after the hook was resumed.
+    if deployment.pod_template_hash() != run.pod_template_hash {
+        return Ok(());
+    }
+
+    info!(
+        "Replaying the suspended run of {} {} for {} {}",
+        H::kind(&()),
+        hook.meta().formatted_name(),
+        deployment.target_kind().kind(),
+        deployment.meta().formatted_name()
+    );
+    steps::start_resumed(ctx, hook, &deployment).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn run(deployment: &str, hash: &str) -> SuspendedRun {
+        SuspendedRun {
+            deployment_kind: TargetKind::Deployment,
+            deployment: deployment.to_string(),
+            pod_template_hash: Some(hash.to_string()),
+            rollout_time: None,
+        }
+    }
+
+    #[test]
+    fn only_the_latest_rollout_of_a_deployment_is_kept() {
+        let mut runs = vec![];
+        record_run(&mut runs, run("docbot-test/api", "1"));
+        record_run(&mut runs, run("docbot-test/web", "2"));
+        record_run(&mut runs, run("docbot-test/api", "3"));
+
+        assert_eq!(
+            runs,
+            vec![run("docbot-test/web", "2"), run("docbot-test/api", "3")]
+        );
+    }
+
+    #[test]
+    fn taken_runs_are_cleared_in_the_written_status() {
+        let mut status = DeploymentHookStatus {
+            suspended_runs: vec![run("docbot-test/api", "1")],
+            ..Default::default()
+        };
+
+        assert_eq!(take_runs(&mut status), vec![run("docbot-test/api", "1")]);
+        assert_eq!(
+            serde_json::to_value(&status).unwrap()["suspendedRuns"],
+            serde_json::json!([])
+        );
+    }
+}
@@ -1,16 +1,78 @@
-use k8s_openapi::api::apps::v1::Deployment;
+use docbot_crd::{HookPhase, Workload};
+use k8s_openapi::api::apps::v1::{DaemonSet, Deployment, StatefulSet};
+use k8s_openapi::api::batch::v1::Job;
+use k8s_openapi::api::core::v1::ObjectReference;
+use kube::core::DynamicObject;
 use sha2::{Digest, Sha256};
 
-pub trait DeploymentExt {
+/// The rollout state of every kind of workload that can trigger hooks.
+pub trait WorkloadExt: Workload {
     fn did_successfully_deploy(&self) -> bool;
 
-    fn pod_template_hash(&self) -> Option<String>;
+    fn did_fail_to_deploy(&self) -> bool {
+        false
+    }
+
+    /// Whether a rollout can be held back until its pre-rollout hooks succeed, by pausing it.
+    fn supports_pre_rollout_gate(&self) -> bool {
+        false
+    }
+
+    /// The revision of the current rollout, as reported by the workload's controller.
+    fn revision(&self) -> Option<String>;
+
+    fn pod_template_hash(&self) -> Option<String> {
+        if let Some(pod_spec) = self.pod_template().and_then(|template| template.spec) {
+            let payload = serde_yaml::to_string(&pod_spec).expect("will always be valid");
+
+            return Some(format!("{:X}", Sha256::digest(payload)));
+        }
+
+        None
+    }
+
+    fn object_reference(&self) -> ObjectReference {
+        let target_kind = self.target_kind();
+
+        ObjectReference {
+            api_version: Some(target_kind.api_version().to_string()),
+            kind: Some(target_kind.kind().to_string()),
+            name: self.meta().name.clone(),
+            namespace: self.meta().namespace.clone(),
+            uid: self.meta().uid.clone(),
+            resource_version: self.meta().resource_version.clone(),
+            ..ObjectReference::default()
+        }
+    }
+}
+
+fn annotation(workload: &impl Workload, key: &str) -> Option<String> {
+    workload
+        .meta()
+        .annotations
+        .as_ref()
+        .and_then(|annotations| annotations.get(key))
+        .cloned()
+}
+
+/// Whether the workload's controller has seen the latest change to its spec.
+fn is_observed(workload: &impl Workload, observed_generation: Option<i64>) -> bool {
+    match (workload.meta().generation, observed_generation) {
+        (Some(generation), Some(observed_generation)) => observed_generation >= generation,
+        _ => true,
+    }
 }
 
-impl DeploymentExt for Deployment {
+impl WorkloadExt for Deployment {
     fn did_successfully_deploy(&self) -> bool {
         // Check to see if the deployment has finished
         if let (Some(status), Some(spec)) = (self.status.as_ref(), self.spec.as_ref()) {
+            // A paused deployment can report all replicas as ready while it is only partially
+            // rolled out.
+            if spec.paused == Some(true) {
+                return false;
+            }
+
             if let (Some(ready_replicas), Some(replicas), Some(deployment_replicas)) =
                 (status.ready_replicas, status.replicas, spec.replicas)
             {
@@ -21,18 +83,208 @@ impl DeploymentExt for Deployment {
             }
         }
 
-        return false;
+        false
     }
 
-    fn pod_template_hash(&self) -> Option<String> {
-        if let Some(spec) = self.spec.as_ref() {
-            if let Some(ref pod_spec) = spec.template.spec {
-                let payload = serde_yaml::to_string(pod_spec).expect("will always be valid");
+    fn did_fail_to_deploy(&self) -> bool {
+        let conditions = self
+            .status
+            .as_ref()
+            .and_then(|status| status.conditions.as_ref());
+
+        // The deployment controller gives up on a rollout by flipping Progressing to False with
+        // the ProgressDeadlineExceeded reason. A ReplicaFailure, e.g. an exceeded quota, can
+        // still clear up before the deadline.
+        conditions.into_iter().flatten().any(|condition| {
+            condition.type_ == "Progressing"
+                && condition.status == "False"
+                && condition.reason.as_deref() == Some("ProgressDeadlineExceeded")
+        })
+    }
+
+    fn supports_pre_rollout_gate(&self) -> bool {
+        true
+    }
 
-                return Some(format!("{:X}", Sha256::digest(payload)));
+    fn revision(&self) -> Option<String> {
+        annotation(self, "deployment.kubernetes.io/revision")
+    }
+}
+
+impl WorkloadExt for StatefulSet {
+    fn did_successfully_deploy(&self) -> bool {
+        if let (Some(status), Some(spec)) = (self.status.as_ref(), self.spec.as_ref()) {
+            let replicas = spec.replicas.unwrap_or(1);
+
+            // Every pod has to run the update revision, not just be ready.
+            return is_observed(self, status.observed_generation)
+                && status.update_revision.is_some()
+                && status.current_revision == status.update_revision
+                && status.replicas == replicas
+                && status.ready_replicas == Some(replicas)
+                && status.updated_replicas == Some(replicas);
+        }
+
+        false
+    }
+
+    fn revision(&self) -> Option<String> {
+        self.status
+            .as_ref()
+            .and_then(|status| status.update_revision.clone())
+    }
+}
+
+impl WorkloadExt for DaemonSet {
+    fn did_successfully_deploy(&self) -> bool {
+        if let Some(status) = self.status.as_ref() {
+            let desired = status.desired_number_scheduled;
+
+            return is_observed(self, status.observed_generation)
+                && status.number_ready == desired
+                && status.updated_number_scheduled == Some(desired);
+        }
+
+        false
+    }
+
+    fn revision(&self) -> Option<String> {
+        self.metadata
+            .generation
+            .map(|generation| generation.to_string())
+    }
+}
+
+/// Argo Rollouts summarises the rollout in `status.phase`.
+impl WorkloadExt for DynamicObject {
+    fn did_successfully_deploy(&self) -> bool {
+        self.data["status"]["phase"] == "Healthy"
+    }
+
+    fn did_fail_to_deploy(&self) -> bool {
+        self.data["status"]["phase"] == "Degraded" || self.data["status"]["abort"] == true
+    }
+
+    fn revision(&self) -> Option<String> {
+        annotation(self, "rollout.argoproj.io/revision")
+    }
+}
+
+pub trait JobExt {
+    fn hook_phase(&self) -> HookPhase;
+}
+
+impl JobExt for Job {
+    fn hook_phase(&self) -> HookPhase {
+        if let Some(status) = self.status.as_ref() {
+            // A job is only finished once the job controller adds a Complete or Failed condition.
+            for condition in status.conditions.iter().flatten() {
+                if condition.status != "True" {
+                    continue;
+                }
+
+                match condition.type_.as_str() {
+                    "Complete" => return HookPhase::Succeeded,
+                    "Failed" => return HookPhase::Failed,
+                    _ => {}
+                }
+            }
+
+            if status.active.unwrap_or(0) > 0 {
+                return HookPhase::Running;
             }
         }
 
-        None
+        HookPhase::Pending
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn deployment_with_conditions(conditions: &str) -> Deployment {
+        let contents = format!(
+            r#"
+---
+apiVersion: apps/v1
+kind: Deployment
+metadata:
+  name: nginx-deployment
+  namespace: docbot-test
+status:
+  conditions:
+{conditions}
+"#
+        );
+
+        serde_yaml::from_str(&contents).unwrap()
+    }
+
+    #[test]
+    fn detecting_finished_statefulset_rollouts() {
+        let statefulset = |current_revision: &str| -> StatefulSet {
+            serde_yaml::from_str(&format!(
+                r#"
+apiVersion: apps/v1
+kind: StatefulSet
+metadata:
+  name: postgres
+  namespace: docbot-test
+  generation: 2
+spec:
+  replicas: 2
+  selector:
+    matchLabels:
+      app: postgres
+  serviceName: postgres
+  template: {{}}
+status:
+  observedGeneration: 2
+  replicas: 2
+  readyReplicas: 2
+  updatedReplicas: 2
+  currentRevision: {current_revision}
+  updateRevision: postgres-7d9f
+"#
+            ))
+            .unwrap()
+        };
+
+        assert!(statefulset("postgres-7d9f").did_successfully_deploy());
+        assert!(!statefulset("postgres-5c4b").did_successfully_deploy());
+    }
+
+    #[test]
+    fn detecting_failed_rollouts() {
+        let exceeded = deployment_with_conditions(
+            r#"
+  - type: Progressing
+    status: "False"
+    reason: ProgressDeadlineExceeded
+"#,
+        );
+        assert!(exceeded.did_fail_to_deploy());
+
+        let replica_failure = deployment_with_conditions(
+            r#"
+  - type: Progressing
+    status: "True"
+    reason: ReplicaSetUpdated
+  - type: ReplicaFailure
+    status: "True"
+    reason: FailedCreate
+"#,
+        );
+        assert!(!replica_failure.did_fail_to_deploy());
+
+        let progressing = deployment_with_conditions(
+            r#"
+  - type: Progressing
+    status: "True"
+    reason: NewReplicaSetAvailable
+"#,
+        );
+        assert!(!progressing.did_fail_to_deploy());
     }
 }
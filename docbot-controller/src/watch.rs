@@ -0,0 +1,27 @@
+use crate::health::Health;
+use crate::metrics;
+use futures::{Stream, StreamExt};
+use kube_runtime::watcher;
+use tracing::info;
+
+/// Keep track of the health of a watcher through its events: every event marks it observed,
+/// every error is logged and counted, and marks it failing until the watcher recovers from it.
+pub fn observed<S, T>(
+    events: S,
+    health: Health,
+    name: String,
+) -> impl Stream<Item = Result<T, watcher::Error>>
+where
+    S: Stream<Item = Result<T, watcher::Error>>,
+{
+    health.watching(&name);
+
+    events.inspect(move |event| match event {
+        Ok(_) => health.observed(&name),
+        Err(err) => {
+            info!("Error while watching {name} changes: {err:?}");
+            metrics::watch_restarted(&name);
+            health.failed(&name);
+        }
+    })
+}
@@ -0,0 +1,189 @@
+use crate::{
+    label_selector_matches, ConcurrencyPolicy, DeploymentHookStatus, DeploymentSelector, Hook,
+    HookTrigger, InternalPodTemplate, RolloutPhase, TargetKind, Workload,
+};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::LabelSelector;
+use kube::CustomResource;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Struct corresponding to the Specification (`spec`) part of the cluster scoped
+/// `ClusterDeploymentHook` resource, directly reflects context of the
+/// `clusterdeploymenthooks.apps.mx.com.yaml` file to be found in this repository.
+/// A single `ClusterDeploymentHook` applies to matching deployments in every selected namespace.
+#[derive(CustomResource, Serialize, Deserialize, Debug, PartialEq, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+#[kube(
+    group = "apps.mx.com",
+    version = "v1",
+    kind = "ClusterDeploymentHook",
+    plural = "clusterdeploymenthooks",
+    derive = "PartialEq",
+    status = "DeploymentHookStatus",
+    printcolumn = r#"{"name":"Phase","type":"string","jsonPath":".status.phase"}"#,
+    printcolumn = r#"{"name":"Last Deployment","type":"string","jsonPath":".status.lastDeployment"}"#,
+    printcolumn = r#"{"name":"Last Job","type":"string","jsonPath":".status.lastJobName"}"#,
+    printcolumn = r#"{"name":"Last Triggered","type":"date","jsonPath":".status.lastTriggeredTime"}"#,
+    printcolumn = r#"{"name":"Age","type":"date","jsonPath":".metadata.creationTimestamp"}"#
+)]
+pub struct ClusterDeploymentHookSpec {
+    /// Namespaces whose deployments this hook applies to. An empty selector selects every
+    /// namespace.
+    pub namespace_selector: LabelSelector,
+    pub selector: DeploymentSelector,
+    /// When the hook runs relative to the rollout of a matching deployment.
+    #[serde(default)]
+    pub phase: RolloutPhase,
+    /// Which outcome of the rollout triggers a `PostRollout` hook.
+    #[serde(default)]
+    pub trigger: HookTrigger,
+    /// What to do when the hook is triggered while a job it created earlier is still running.
+    /// Only the jobs in the namespace the new job is created in are considered.
+    #[serde(default)]
+    pub concurrency_policy: ConcurrencyPolicy,
+    /// The namespace jobs are created in and named pod templates are looked up from. Defaults
+    /// to the namespace of the triggering deployment.
+    pub job_namespace: Option<String>,
+    /// Number of succeeded jobs of this hook to keep, across all namespaces.
+    pub successful_jobs_history_limit: Option<u32>,
+    /// Number of failed jobs of this hook to keep, across all namespaces.
+    pub failed_jobs_history_limit: Option<u32>,
+    pub template: InternalPodTemplate,
+}
+
+impl Hook for ClusterDeploymentHook {
+    fn pod_template(&self) -> &InternalPodTemplate {
+        &self.spec.template
+    }
+
+    fn hook_status(&self) -> Option<&DeploymentHookStatus> {
+        self.status.as_ref()
+    }
+
+    fn target_kind(&self) -> TargetKind {
+        self.spec.selector.target_kind
+    }
+
+    fn deployment_selector(&self) -> &DeploymentSelector {
+        &self.spec.selector
+    }
+
+    fn rollout_phase(&self) -> RolloutPhase {
+        self.spec.phase
+    }
+
+    fn trigger(&self) -> HookTrigger {
+        self.spec.trigger
+    }
+
+    fn concurrency_policy(&self) -> ConcurrencyPolicy {
+        self.spec.concurrency_policy
+    }
+
+    fn successful_jobs_history_limit(&self) -> Option<u32> {
+        self.spec.successful_jobs_history_limit
+    }
+
+    fn failed_jobs_history_limit(&self) -> Option<u32> {
+        self.spec.failed_jobs_history_limit
+    }
+
+    fn does_match_deployment(
+        &self,
+        deployment: &impl Workload,
+        namespace_labels: &BTreeMap<String, String>,
+    ) -> bool {
+        if !label_selector_matches(&self.spec.namespace_selector, namespace_labels) {
+            return false;
+        }
+
+        self.spec.selector.selects(deployment)
+    }
+
+    fn job_namespace(&self, deployment: &impl Workload) -> String {
+        self.spec
+            .job_namespace
+            .clone()
+            .or_else(|| deployment.meta().namespace.clone())
+            .unwrap_or_else(|| "default".to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use k8s_openapi::api::apps::v1::Deployment;
+    use serde_json::json;
+
+    fn cluster_hook(job_namespace: Option<&str>) -> ClusterDeploymentHook {
+        serde_json::from_value(json!({
+            "apiVersion": "apps.mx.com/v1",
+            "kind": "ClusterDeploymentHook",
+            "metadata": { "name": "notify-cmdb" },
+            "spec": {
+                "namespaceSelector": { "matchLabels": { "compliance": "pci" } },
+                "selector": { "labels": { "compliance": "pci" } },
+                "jobNamespace": job_namespace,
+                "template": { "name": "cmdb-notifier" }
+            }
+        }))
+        .unwrap()
+    }
+
+    fn deployment() -> Deployment {
+        serde_json::from_value(json!({
+            "metadata": {
+                "name": "payments",
+                "namespace": "team-payments",
+                "labels": { "compliance": "pci" }
+            }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn matches_deployments_in_selected_namespaces() {
+        let hook = cluster_hook(None);
+        let pci = BTreeMap::from([("compliance".to_string(), "pci".to_string())]);
+
+        assert!(hook.does_match_deployment(&deployment(), &pci));
+        assert!(!hook.does_match_deployment(&deployment(), &BTreeMap::new()));
+    }
+
+    #[test]
+    fn job_namespace_defaults_to_the_deployment_namespace() {
+        assert_eq!(
+            cluster_hook(None).job_namespace(&deployment()),
+            "team-payments"
+        );
+        assert_eq!(
+            cluster_hook(Some("platform")).job_namespace(&deployment()),
+            "platform"
+        );
+    }
+
+    #[test]
+    fn runs_post_rollout_on_success_unless_configured_otherwise() {
+        let hook = cluster_hook(None);
+        assert_eq!(hook.rollout_phase(), RolloutPhase::PostRollout);
+        assert_eq!(hook.trigger(), HookTrigger::OnSuccess);
+        assert_eq!(hook.concurrency_policy(), ConcurrencyPolicy::Allow);
+
+        let hook: ClusterDeploymentHook = serde_json::from_value(json!({
+            "apiVersion": "apps.mx.com/v1",
+            "kind": "ClusterDeploymentHook",
+            "metadata": { "name": "snapshot-volumes" },
+            "spec": {
+                "namespaceSelector": {},
+                "selector": { "labels": { "compliance": "pci" } },
+                "phase": "PreRollout",
+                "concurrencyPolicy": "Forbid",
+                "template": { "name": "volume-snapshotter" }
+            }
+        }))
+        .unwrap();
+        assert_eq!(hook.rollout_phase(), RolloutPhase::PreRollout);
+        assert_eq!(hook.concurrency_policy(), ConcurrencyPolicy::Forbid);
+    }
+}
@@ -0,0 +1,125 @@
+use crate::Workload;
+use k8s_openapi::api::core::v1::PodTemplateSpec;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// Derives the hook pod from the pod template of the triggering deployment, so the job shares
+/// its env, volumes, service account and image pull secrets.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct FromDeployment {
+    /// Name of the deployment container whose command and args are replaced.
+    pub container: String,
+    /// Replaces the command of the container, leaving it unset runs the image entrypoint.
+    pub command: Option<Vec<String>>,
+    /// Replaces the args of the container.
+    pub args: Option<Vec<String>>,
+}
+
+impl FromDeployment {
+    /// Copy the deployment's pod template with the command of the chosen container replaced
+    /// and the probes and ports of every container removed, a job pod is not meant to serve
+    /// traffic.
+    pub fn pod_template_spec(
+        &self,
+        deployment: &impl Workload,
+    ) -> Result<PodTemplateSpec, Box<dyn std::error::Error>> {
+        let mut template = deployment
+            .pod_template()
+            .ok_or("Deployment has no pod template to derive the hook pod from")?;
+
+        // The labels select the pod into the deployment's services, keep the job pods out.
+        if let Some(ref mut metadata) = template.metadata {
+            metadata.labels = None;
+        }
+
+        let pod_spec = template
+            .spec
+            .as_mut()
+            .ok_or("Deployment has no pod spec to derive the hook pod from")?;
+
+        let container = pod_spec
+            .containers
+            .iter_mut()
+            .find(|container| container.name == self.container)
+            .ok_or_else(|| format!("Deployment has no container {}", self.container))?;
+        container.command = self.command.clone();
+        container.args = self.args.clone();
+
+        let init_containers = pod_spec.init_containers.iter_mut().flatten();
+        for container in pod_spec.containers.iter_mut().chain(init_containers) {
+            container.liveness_probe = None;
+            container.readiness_probe = None;
+            container.startup_probe = None;
+            container.ports = None;
+        }
+
+        Ok(template)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use k8s_openapi::api::apps::v1::Deployment;
+    use serde_json::json;
+
+    fn deployment() -> Deployment {
+        serde_json::from_value(json!({
+            "metadata": { "name": "nginx-deployment", "namespace": "docbot-test" },
+            "spec": {
+                "selector": { "matchLabels": { "app": "nginx" } },
+                "template": {
+                    "metadata": { "labels": { "app": "nginx" } },
+                    "spec": {
+                        "serviceAccountName": "nginx",
+                        "containers": [{
+                            "name": "nginx",
+                            "image": "nginx:1.14.2",
+                            "args": ["serve"],
+                            "envFrom": [{ "configMapRef": { "name": "config-nginx-test" } }],
+                            "ports": [{ "containerPort": 80 }],
+                            "readinessProbe": { "httpGet": { "path": "/", "port": 80 } }
+                        }]
+                    }
+                }
+            }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn derives_the_pod_from_the_deployment() {
+        let from_deployment = FromDeployment {
+            container: "nginx".to_string(),
+            command: Some(vec!["./migrate".to_string()]),
+            args: None,
+        };
+        let template = from_deployment.pod_template_spec(&deployment()).unwrap();
+
+        let expected: PodTemplateSpec = serde_json::from_value(json!({
+            "metadata": {},
+            "spec": {
+                "serviceAccountName": "nginx",
+                "containers": [{
+                    "name": "nginx",
+                    "image": "nginx:1.14.2",
+                    "command": ["./migrate"],
+                    "envFrom": [{ "configMapRef": { "name": "config-nginx-test" } }]
+                }]
+            }
+        }))
+        .unwrap();
+        assert_eq!(template, expected);
+    }
+
+    #[test]
+    fn unknown_containers_are_an_error() {
+        let from_deployment = FromDeployment {
+            container: "web".to_string(),
+            command: None,
+            args: None,
+        };
+        assert!(from_deployment.pod_template_spec(&deployment()).is_err());
+    }
+}
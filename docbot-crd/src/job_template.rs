@@ -0,0 +1,331 @@
+use futures::Stream;
+use k8s_openapi::api::batch::v1::Job;
+use k8s_openapi::api::batch::v1beta1::JobTemplateSpec;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+use kube::{
+    api::ListParams,
+    client::Client,
+    core::{ApiResource, DynamicObject, GroupVersionKind},
+    Api,
+};
+use kube_runtime::utils::StreamBackoff;
+use kube_runtime::watcher::{self, watcher};
+use lru::LruCache;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tracing::info;
+
+/// The kinds of object a job template can be taken from.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq, Hash, Clone, Copy, JsonSchema)]
+pub enum JobTemplateKind {
+    /// The `jobTemplate` of a `CronJob`, usually a suspended one.
+    #[default]
+    CronJob,
+    /// The spec of an existing `Job`.
+    Job,
+}
+
+/// Points at the object whose job template the generated job is based on.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct JobTemplateRef {
+    #[serde(default)]
+    pub kind: JobTemplateKind,
+    /// Name of the object in the job's namespace, or `namespace/name` of one in another
+    /// namespace that allows the job's namespace to use it.
+    pub name: String,
+}
+
+/// A job template along with the metadata of the object it was taken from, which carries the
+/// annotations deciding which namespaces may use it.
+#[derive(Debug, Clone)]
+pub struct JobTemplateSource {
+    pub metadata: ObjectMeta,
+    pub template: JobTemplateSpec,
+}
+
+/// `CronJob`s are read through the `batch/v1` api as dynamic objects, the types we build
+/// against only know the `batch/v1beta1` one that newer clusters no longer serve.
+fn cron_job_api_resource() -> ApiResource {
+    ApiResource::from_gvk(&GroupVersionKind::gvk("batch", "v1", "CronJob"))
+}
+
+fn from_cron_job(cron_job: DynamicObject) -> Result<JobTemplateSource, Box<dyn std::error::Error>> {
+    let template = serde_json::from_value(cron_job.data["spec"]["jobTemplate"].clone())?;
+    Ok(JobTemplateSource {
+        metadata: cron_job.metadata,
+        template,
+    })
+}
+
+/// Turn an existing job back into a template, dropping what the job controller set on it.
+fn from_job(job: Job) -> JobTemplateSource {
+    let mut spec = job.spec.unwrap_or_default();
+    spec.selector = None;
+    spec.manual_selector = None;
+    if let Some(ref mut labels) = spec
+        .template
+        .metadata
+        .as_mut()
+        .and_then(|metadata| metadata.labels.as_mut())
+    {
+        labels.remove("controller-uid");
+        labels.remove("job-name");
+    }
+
+    let labels = job.metadata.labels.clone().map(|mut labels| {
+        labels.remove("controller-uid");
+        labels.remove("job-name");
+        labels
+    });
+
+    JobTemplateSource {
+        template: JobTemplateSpec {
+            metadata: Some(ObjectMeta {
+                labels,
+                ..ObjectMeta::default()
+            }),
+            spec: Some(spec),
+        },
+        metadata: job.metadata,
+    }
+}
+
+/// Kind, namespace and name of the object a job template was taken from.
+type JobTemplateKey = (JobTemplateKind, String, String);
+
+/// Caches the job templates hooks are based on, the same way `PodTemplateService` caches pod
+/// templates.
+#[derive(Clone)]
+pub struct JobTemplateService {
+    cache: Arc<Mutex<LruCache<JobTemplateKey, JobTemplateSource>>>,
+    client: Client,
+}
+
+impl JobTemplateService {
+    pub fn new(client: Client) -> Self {
+        let cache = Arc::new(Mutex::new(LruCache::new(NonZeroUsize::new(1024).unwrap())));
+
+        Self { cache, client }
+    }
+
+    pub(crate) fn client(&self) -> &Client {
+        &self.client
+    }
+
+    pub async fn get(
+        &self,
+        kind: JobTemplateKind,
+        name: &str,
+        namespace: &str,
+    ) -> Result<JobTemplateSource, Box<dyn std::error::Error>> {
+        let mut locked = self.cache.lock().await;
+
+        let cache_key = (kind, namespace.to_string(), name.to_string());
+        if let Some(source) = locked.get(&cache_key) {
+            return Ok(source.clone());
+        }
+        info!("Cache miss: calling the api");
+
+        let source = match kind {
+            JobTemplateKind::CronJob => {
+                let api: Api<DynamicObject> =
+                    Api::namespaced_with(self.client.clone(), namespace, &cron_job_api_resource());
+                from_cron_job(api.get(name).await?)?
+            }
+            JobTemplateKind::Job => {
+                let api: Api<Job> = Api::namespaced(self.client.clone(), namespace);
+                from_job(api.get(name).await?)
+            }
+        };
+
+        locked.push(cache_key, source.clone());
+
+        Ok(source)
+    }
+
+    /// The changes of the `CronJob`s, which keep the cached ones up to date through
+    /// `record_change`. Jobs can't change their template, so they are not watched.
+    pub fn changes(
+        &self,
+    ) -> impl Stream<Item = Result<watcher::Event<DynamicObject>, watcher::Error>> + Send {
+        let api: Api<DynamicObject> = Api::all_with(self.client.clone(), &cron_job_api_resource());
+        StreamBackoff::new(
+            watcher(api, ListParams::default()),
+            watcher::default_backoff(),
+        )
+    }
+
+    /// Refresh or evict the cached `CronJob`s a change is about.
+    pub async fn record_change(&self, event: watcher::Event<DynamicObject>) {
+        apply_change(&mut *self.cache.lock().await, event);
+    }
+}
+
+/// Only what is cached is refreshed, most cron jobs are not hook templates.
+fn apply_change(
+    cache: &mut LruCache<JobTemplateKey, JobTemplateSource>,
+    event: watcher::Event<DynamicObject>,
+) {
+    match event {
+        watcher::Event::Applied(cron_job) => refresh(cache, cron_job),
+        watcher::Event::Deleted(cron_job) => {
+            let cache_key = cron_job_key(&cron_job);
+            if cache.pop(&cache_key).is_some() {
+                info!(
+                    "Witnessed the deletion of CronJob {}/{}",
+                    cache_key.1, cache_key.2
+                );
+            }
+        }
+        // The cron jobs deleted while we weren't watching are missing from the list.
+        watcher::Event::Restarted(cron_jobs) => {
+            let listed: Vec<JobTemplateKey> = cron_jobs.iter().map(cron_job_key).collect();
+            let deleted: Vec<JobTemplateKey> = cache
+                .iter()
+                .map(|(cache_key, _)| cache_key)
+                .filter(|cache_key| {
+                    cache_key.0 == JobTemplateKind::CronJob && !listed.contains(cache_key)
+                })
+                .cloned()
+                .collect();
+            for cache_key in deleted {
+                cache.pop(&cache_key);
+            }
+            for cron_job in cron_jobs {
+                refresh(cache, cron_job);
+            }
+        }
+    }
+}
+
+fn cron_job_key(cron_job: &DynamicObject) -> JobTemplateKey {
+    (
+        JobTemplateKind::CronJob,
+        cron_job.metadata.namespace.clone().unwrap_or_default(),
+        cron_job.metadata.name.clone().unwrap_or_default(),
+    )
+}
+
+/// Replace the cached template of a `CronJob` that changed, dropping it when it can't be read
+/// anymore so the next `get` reports why.
+fn refresh(cache: &mut LruCache<JobTemplateKey, JobTemplateSource>, cron_job: DynamicObject) {
+    let cache_key = cron_job_key(&cron_job);
+    if !cache.contains(&cache_key) {
+        return;
+    }
+
+    info!(
+        "Witnessed a change of CronJob {}/{}",
+        cache_key.1, cache_key.2
+    );
+    match from_cron_job(cron_job) {
+        Ok(source) => {
+            cache.push(cache_key, source);
+        }
+        Err(err) => {
+            info!(
+                "Dropping CronJob {}/{} from the cache, error: {:?}",
+                cache_key.1, cache_key.2, err
+            );
+            cache.pop(&cache_key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn reads_the_job_template_of_a_cron_job() {
+        let cron_job: DynamicObject = serde_json::from_value(json!({
+            "apiVersion": "batch/v1",
+            "kind": "CronJob",
+            "metadata": { "name": "db-migrate", "namespace": "docbot-test" },
+            "spec": {
+                "suspend": true,
+                "schedule": "0 0 1 1 *",
+                "jobTemplate": {
+                    "metadata": { "labels": { "app": "db-migrate" } },
+                    "spec": {
+                        "backoffLimit": 4,
+                        "template": {
+                            "spec": {
+                                "restartPolicy": "OnFailure",
+                                "containers": [{ "name": "migrate", "image": "migrate:1.0" }]
+                            }
+                        }
+                    }
+                }
+            }
+        }))
+        .unwrap();
+
+        let source = from_cron_job(cron_job).unwrap();
+        assert_eq!(source.metadata.name.as_deref(), Some("db-migrate"));
+        assert_eq!(source.template.spec.unwrap().backoff_limit, Some(4));
+    }
+
+    #[test]
+    fn strips_what_the_job_controller_set() {
+        let job: Job = serde_json::from_value(json!({
+            "metadata": {
+                "name": "db-migrate-manual",
+                "labels": { "app": "db-migrate", "job-name": "db-migrate-manual", "controller-uid": "1234" }
+            },
+            "spec": {
+                "selector": { "matchLabels": { "controller-uid": "1234" } },
+                "template": {
+                    "metadata": { "labels": { "job-name": "db-migrate-manual", "controller-uid": "1234" } },
+                    "spec": { "containers": [{ "name": "migrate", "image": "migrate:1.0" }] }
+                }
+            }
+        }))
+        .unwrap();
+
+        let template = from_job(job).template;
+        assert_eq!(
+            template.metadata.unwrap().labels.unwrap(),
+            [("app".to_string(), "db-migrate".to_string())].into()
+        );
+        let spec = template.spec.unwrap();
+        assert_eq!(spec.selector, None);
+        assert_eq!(spec.template.metadata.unwrap().labels.unwrap().len(), 0);
+    }
+    #[test]
+    fn deleted_cron_jobs_are_evicted() {
+        let cron_job = |name: &str| -> DynamicObject {
+            serde_json::from_value(json!({
+                "apiVersion": "batch/v1",
+                "kind": "CronJob",
+                "metadata": { "name": name, "namespace": "docbot-test" },
+                "spec": { "jobTemplate": { "spec": { "template": {} } } }
+            }))
+            .unwrap()
+        };
+        let mut cache = LruCache::new(NonZeroUsize::new(8).unwrap());
+        for name in ["db-migrate", "warm-cache", "notify"] {
+            cache.push(
+                cron_job_key(&cron_job(name)),
+                from_cron_job(cron_job(name)).unwrap(),
+            );
+        }
+
+        apply_change(&mut cache, watcher::Event::Deleted(cron_job("db-migrate")));
+        assert!(!cache.contains(&cron_job_key(&cron_job("db-migrate"))));
+
+        // A cron job deleted while the watch was down is missing from the list.
+        apply_change(
+            &mut cache,
+            watcher::Event::Restarted(vec![cron_job("warm-cache"), cron_job("other")]),
+        );
+        assert!(cache.contains(&cron_job_key(&cron_job("warm-cache"))));
+        assert!(!cache.contains(&cron_job_key(&cron_job("notify"))));
+        assert!(!cache.contains(&cron_job_key(&cron_job("other"))));
+    }
+}
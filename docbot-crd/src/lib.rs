@@ -1,19 +1,50 @@
-use k8s_openapi::api::apps::v1::Deployment;
+use k8s_openapi::api::batch::v1beta1::JobTemplateSpec;
 use k8s_openapi::api::core::v1::PodTemplate;
 use k8s_openapi::api::core::v1::PodTemplateSpec;
-use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
-use kube::CustomResource;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{
+    LabelSelector, LabelSelectorRequirement, ObjectMeta,
+};
+use kube::{CustomResource, Resource};
 
 use schemars::JsonSchema;
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::collections::BTreeMap;
+use std::fmt::Debug;
 use tracing::info;
 
+mod cluster_hook;
+mod from_deployment;
+mod job_template;
+mod metrics;
 mod pod_template;
+mod selector;
+mod status;
+mod template_ref;
+mod workload;
 
+pub use cluster_hook::{ClusterDeploymentHook, ClusterDeploymentHookSpec};
+pub use from_deployment::FromDeployment;
+pub use job_template::{JobTemplateKind, JobTemplateRef, JobTemplateService};
 pub use pod_template::PodTemplateService;
+pub use selector::{label_selector_matches, labels_match, selector_requirements};
+pub use status::{
+    DeploymentHookStatus, HookCondition, HookPhase, HookRun, JobCreationRetry, SuspendedRun,
+    CONDITION_INVALID_SPEC, CONDITION_PROGRESSING, CONDITION_ROLLED_BACK,
+    CONDITION_ROLLOUT_BLOCKED, CONDITION_SUCCEEDED,
+};
+pub use template_ref::{ALLOWED_NAMESPACES_ANNOTATION, ALLOWED_NAMESPACE_SELECTOR_ANNOTATION};
+pub use workload::{rollout_api_resource, TargetKind, Workload};
 
-/// The default job ttl is 72 hours.
+/// Workloads opt in to hooks with this label.
+pub const HOOKS_LABEL: &str = "apps.mx.com/deploymenthook";
+
+/// Label set on every job created by docbot, the value is the name of the owning hook.
+pub const HOOK_NAME_LABEL: &str = "apps.mx.com/deploymenthook-name";
+
+/// Annotation that runs a hook right away. Every new value of the annotation runs the hook once.
+pub const RUN_NOW_ANNOTATION: &str = "apps.mx.com/run-now";
+
+/// The default job ttl is 24 hours.
 fn default_job_ttl_seconds_after_finished() -> Option<i32> {
     Some(86400)
 }
@@ -30,17 +61,149 @@ fn default_job_ttl_seconds_after_finished() -> Option<i32> {
     kind = "DeploymentHook",
     plural = "deploymenthooks",
     derive = "PartialEq",
+    status = "DeploymentHookStatus",
+    printcolumn = r#"{"name":"Phase","type":"string","jsonPath":".status.phase"}"#,
+    printcolumn = r#"{"name":"Last Deployment","type":"string","jsonPath":".status.lastDeployment"}"#,
+    printcolumn = r#"{"name":"Last Job","type":"string","jsonPath":".status.lastJobName"}"#,
+    printcolumn = r#"{"name":"Last Triggered","type":"date","jsonPath":".status.lastTriggeredTime"}"#,
+    printcolumn = r#"{"name":"Age","type":"date","jsonPath":".metadata.creationTimestamp"}"#,
     namespaced
 )]
 pub struct DeploymentHookSpec {
     pub selector: DeploymentSelector,
+    /// By default a hook only matches deployments in its own namespace. When set, the hook
+    /// instead matches deployments in every namespace whose labels match this selector.
+    pub namespace_selector: Option<LabelSelector>,
+    /// When the hook runs relative to the rollout of a matching deployment.
+    #[serde(default)]
+    pub phase: RolloutPhase,
+    /// Which outcome of the rollout triggers a `PostRollout` hook.
+    #[serde(default)]
+    pub trigger: HookTrigger,
+    /// What to do when the hook is triggered while a job it created earlier is still running.
+    #[serde(default)]
+    pub concurrency_policy: ConcurrencyPolicy,
+    /// Names of hooks in the same namespace that have to succeed for the triggering deployment
+    /// before this hook runs. Hooks that don't match the deployment are ignored.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub run_after: Vec<String>,
+    /// What to do with the deployment when a job of a `PostRollout` hook fails.
+    #[serde(default)]
+    pub on_failure: FailurePolicy,
+    /// Stops the hook from running on new rollouts. The rollouts that happen in the meantime
+    /// are recorded in the status and handled according to `resumePolicy` once unsuspended.
+    #[serde(default)]
+    pub suspend: bool,
+    #[serde(default)]
+    pub resume_policy: ResumePolicy,
+    /// Number of succeeded jobs of this hook to keep, older ones are deleted. Unset keeps them
+    /// all and leaves cleaning up to `ttlSecondsAfterFinished`.
+    pub successful_jobs_history_limit: Option<u32>,
+    /// Number of failed jobs of this hook to keep.
+    pub failed_jobs_history_limit: Option<u32>,
     pub template: InternalPodTemplate,
 }
 
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq, Clone, Copy, JsonSchema)]
+pub enum RolloutPhase {
+    /// Run the job once the deployment finished rolling out.
+    #[default]
+    PostRollout,
+    /// Pause the deployment as soon as a new pod template is seen, run the job, and only resume
+    /// the rollout once the job succeeded.
+    PreRollout,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq, Clone, Copy, JsonSchema)]
+pub enum HookTrigger {
+    /// Run the job when the rollout completed successfully.
+    #[default]
+    OnSuccess,
+    /// Run the job when the rollout failed because it exceeded its progress deadline, or was
+    /// degraded or aborted for an Argo `Rollout`. Not supported for StatefulSets and DaemonSets.
+    OnFailure,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq, Clone, Copy, JsonSchema)]
+pub enum FailurePolicy {
+    /// Leave the deployment as it is.
+    #[default]
+    Ignore,
+    /// Roll the deployment back to the pod template of its previous revision.
+    Rollback,
+}
+
+/// What to do with the rollouts recorded while a hook was suspended.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq, Clone, Copy, JsonSchema)]
+pub enum ResumePolicy {
+    /// Forget about them.
+    #[default]
+    Drop,
+    /// Run the hook for the rollouts whose pod template is still the current one.
+    Replay,
+}
+
+/// Mirrors the `concurrencyPolicy` of a `CronJob`.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq, Clone, Copy, JsonSchema)]
+pub enum ConcurrencyPolicy {
+    /// Create the new job alongside the running ones.
+    #[default]
+    Allow,
+    /// Skip the new job while an earlier one is still running.
+    Forbid,
+    /// Delete the running jobs and create the new one.
+    Replace,
+}
+
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct DeploymentSelector {
+    /// The kind of workload the hook applies to.
+    #[serde(default)]
+    pub target_kind: TargetKind,
+    /// Exact match labels. This predates `matchLabels` and behaves identically, when both are
+    /// set a deployment has to match all of them.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
     pub labels: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub match_labels: BTreeMap<String, String>,
+    /// Set based requirements supporting the `In`, `NotIn`, `Exists` and `DoesNotExist`
+    /// operators.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub match_expressions: Vec<LabelSelectorRequirement>,
+}
+
+impl DeploymentSelector {
+    pub fn matches(&self, labels: &BTreeMap<String, String>) -> bool {
+        labels_match(&self.labels, &[], labels)
+            && labels_match(&self.match_labels, &self.match_expressions, labels)
+    }
+
+    /// The selector in the syntax of the `labelSelector` list parameter, narrowed down to the
+    /// workloads that opted in to hooks. The listed workloads still have to be checked with
+    /// `selects`, requirements the parameter can't express are left out.
+    pub fn list_selector(&self) -> String {
+        let mut requirements = vec![HOOKS_LABEL.to_string()];
+        requirements.extend(selector_requirements(&self.labels, &[]));
+        requirements.extend(selector_requirements(
+            &self.match_labels,
+            &self.match_expressions,
+        ));
+        requirements.join(",")
+    }
+
+    /// Whether the workload is of the target kind and its labels match.
+    pub fn selects(&self, workload: &impl Workload) -> bool {
+        if workload.target_kind() != self.target_kind {
+            return false;
+        }
+
+        if let Some(ref labels) = workload.meta().labels {
+            self.matches(labels)
+        } else {
+            false
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone, JsonSchema)]
@@ -48,54 +211,154 @@ pub struct DeploymentSelector {
 pub struct InternalPodTemplate {
     #[serde(default = "default_job_ttl_seconds_after_finished")]
     pub ttl_seconds_after_finished: Option<i32>,
+    /// Name of a `PodTemplate` in the job's namespace, or `namespace/name` of one in another
+    /// namespace that allows the job's namespace to use it.
     pub name: Option<String>,
     pub spec: Option<PodTemplateSpec>,
+    /// Start from the pod template of the triggering deployment instead of `name` or `spec`.
+    pub from_deployment: Option<FromDeployment>,
+    /// Base the job on the job template of a `CronJob` or an existing `Job`, including its job
+    /// settings. Takes precedence over `name`, `spec` and `fromDeployment`.
+    pub job_template_ref: Option<JobTemplateRef>,
+    /// Containers of the job that run the image of a container in the triggering deployment
+    /// instead of the image set in the template.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub use_deployment_image: Vec<DeploymentImage>,
+    /// Overrides merged into the spec of the generated job.
+    pub job_spec: Option<JobSpecOverrides>,
 }
 
-impl DeploymentHook {
+/// The subset of the `JobSpec` that can be tuned per hook. Fields that are not set keep the
+/// values docbot uses by default.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct JobSpecOverrides {
+    /// Number of retries before the job is marked failed. Defaults to 1.
+    pub backoff_limit: Option<i32>,
+    /// How long the job may run before it is terminated and marked failed.
+    pub active_deadline_seconds: Option<i64>,
+    pub parallelism: Option<i32>,
+    pub completions: Option<i32>,
+}
+
+/// Replaces the image of a job container with the image of a deployment container, resolved
+/// when the job is created.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct DeploymentImage {
+    /// Name of the container in the pod template.
+    pub container: String,
+    /// Name of the container in the deployment whose image is used. Defaults to `container`.
+    pub deployment_container: Option<String>,
+}
+
+impl DeploymentImage {
+    pub fn deployment_container(&self) -> &str {
+        self.deployment_container
+            .as_deref()
+            .unwrap_or(&self.container)
+    }
+}
+
+impl InternalPodTemplate {
     pub fn has_embedded_pod_template(&self) -> bool {
-        // Check to see if the template was embedded in the struct.
-        if let Some(ref _template) = self.spec.template.spec {
-            true
-        } else {
-            false
-        }
+        // Check to see if the template was embedded in the struct. Templates derived from the
+        // deployment or taken from a job template don't depend on a `PodTemplate` either.
+        self.spec.is_some() || self.from_deployment.is_some() || self.job_template_ref.is_some()
     }
 
-    pub fn get_pod_template_name(&self) -> Option<String> {
-        self.spec.template.name.clone()
+    /// Look up the referenced job template, if any, the pod template is then taken from it
+    /// instead of `get_pod_template`.
+    pub async fn get_job_template(
+        &self,
+        namespace: &str,
+        job_template_service: &JobTemplateService,
+    ) -> Result<Option<JobTemplateSpec>, Box<dyn std::error::Error>> {
+        let reference = match self.job_template_ref {
+            Some(ref reference) => reference,
+            None => return Ok(None),
+        };
+
+        let (template_namespace, name) =
+            template_ref::parse_template_reference(&reference.name, namespace);
+        let source = job_template_service
+            .get(reference.kind, name, template_namespace)
+            .await?;
+
+        if template_namespace != namespace {
+            template_ref::ensure_allowed(
+                job_template_service.client(),
+                &source.metadata,
+                namespace,
+            )
+            .await?;
+        }
+
+        Ok(Some(source.template))
     }
 
+    /// Resolve the pod template from the triggering deployment, the embedded spec or by
+    /// looking up the named `PodTemplate` in `namespace`. A `namespace/name` reference looks the
+    /// template up in another namespace, if that template allows `namespace` to use it, and a
+    /// deployment in another namespace has to allow it the same way.
     pub async fn get_pod_template(
         &self,
+        namespace: &str,
+        deployment: &impl Workload,
         pod_template_service: PodTemplateService,
     ) -> Result<PodTemplate, Box<dyn std::error::Error>> {
+        if let Some(ref from_deployment) = self.from_deployment {
+            // The pod template carries the env, service account and volumes of its namespace.
+            if deployment.meta().namespace.as_deref() != Some(namespace) {
+                template_ref::ensure_allowed(
+                    pod_template_service.client(),
+                    deployment.meta(),
+                    namespace,
+                )
+                .await?;
+            }
+
+            return Ok(PodTemplate {
+                metadata: ObjectMeta {
+                    namespace: Some(namespace.to_string()),
+                    ..ObjectMeta::default()
+                },
+                template: Some(from_deployment.pod_template_spec(deployment)?),
+            });
+        }
+
         // Check to see if the template was embedded in the struct.
-        if let Some(ref template) = self.spec.template.spec {
+        if let Some(ref template) = self.spec {
             // HACK: Mock a PodTemplate for now to keep things simple.
             return Ok(PodTemplate {
                 metadata: ObjectMeta {
-                    namespace: self.metadata.namespace.clone(),
+                    namespace: Some(namespace.to_string()),
                     ..ObjectMeta::default()
                 },
                 template: Some(template.clone()),
             });
         }
 
-        let namespace = &self
-            .metadata
-            .namespace
-            .clone()
-            .unwrap_or_else(|| "default".to_string());
+        if let Some(ref reference) = self.name {
+            let (template_namespace, name) =
+                template_ref::parse_template_reference(reference, namespace);
+            let found = pod_template_service.get(name, template_namespace).await?;
+            if let Some(specific_pod_template) = found {
+                // Templates of other namespaces have to opt in to being used by this one.
+                if template_namespace != namespace {
+                    template_ref::ensure_allowed(
+                        pod_template_service.client(),
+                        &specific_pod_template.metadata,
+                        namespace,
+                    )
+                    .await?;
+                }
 
-        if let Some(ref name) = self.spec.template.name {
-            if let Some(specific_pod_template) = pod_template_service.get(&name, &namespace).await?
-            {
                 // Print containers and their images
                 if let Some(template) = &specific_pod_template.template {
                     if let Some(pod_spec) = &template.spec {
                         for container in &pod_spec.containers {
-                            info!("Container Image for template {} in namespace {:?} from PodTemplate cache {} : {:?}", name, self.metadata.namespace, container.name, container.image);
+                            info!("Container Image for template {} in namespace {} from PodTemplate cache {} : {:?}", name, namespace, container.name, container.image);
                         }
                     }
                 } else {
@@ -103,26 +366,327 @@ impl DeploymentHook {
                 }
                 return Ok(specific_pod_template);
             } else {
-                return Err(format!("Could not find pod template at {namespace}/{name}").into());
+                return Err(
+                    format!("Could not find pod template at {template_namespace}/{name}").into(),
+                );
             }
         }
 
-        Err(format!(
-            "Could not find a way to return a pod template for deployment hook {:?}",
-            self.metadata.name
-        )
-        .into())
+        Err("Could not find a way to return a pod template, neither name nor spec are set".into())
+    }
+}
+
+/// Behaviour shared by the namespaced `DeploymentHook` and the cluster scoped
+/// `ClusterDeploymentHook`, so the controller can run both through the same job generation path.
+pub trait Hook:
+    Resource<DynamicType = ()> + Clone + Debug + Serialize + DeserializeOwned + Send + Sync + 'static
+{
+    fn pod_template(&self) -> &InternalPodTemplate;
+
+    fn hook_status(&self) -> Option<&DeploymentHookStatus>;
+
+    /// The kind of workload the hook selects.
+    fn target_kind(&self) -> TargetKind;
+
+    fn deployment_selector(&self) -> &DeploymentSelector;
+
+    /// The namespace all deployments the hook can match live in, if it is limited to one.
+    fn deployment_namespace(&self) -> Option<&str> {
+        None
+    }
+
+    /// The token of the run-now annotation, if the hook has one.
+    fn run_now_token(&self) -> Option<&str> {
+        self.meta()
+            .annotations
+            .as_ref()?
+            .get(RUN_NOW_ANNOTATION)
+            .map(String::as_str)
+    }
+
+    fn rollout_phase(&self) -> RolloutPhase {
+        RolloutPhase::PostRollout
+    }
+
+    fn trigger(&self) -> HookTrigger {
+        HookTrigger::OnSuccess
+    }
+
+    fn concurrency_policy(&self) -> ConcurrencyPolicy {
+        ConcurrencyPolicy::Allow
+    }
+
+    fn suspended(&self) -> bool {
+        false
+    }
+
+    fn resume_policy(&self) -> ResumePolicy {
+        ResumePolicy::Drop
+    }
+
+    fn successful_jobs_history_limit(&self) -> Option<u32>;
+
+    fn failed_jobs_history_limit(&self) -> Option<u32>;
+
+    /// Names of the hooks in the same namespace this hook runs after.
+    fn run_after(&self) -> &[String] {
+        &[]
+    }
+
+    fn does_match_deployment(
+        &self,
+        deployment: &impl Workload,
+        namespace_labels: &BTreeMap<String, String>,
+    ) -> bool;
+
+    /// The namespace jobs are created in, and named pod templates are resolved from, when the
+    /// hook is triggered by `deployment`, which may be any kind of workload.
+    fn job_namespace(&self, deployment: &impl Workload) -> String;
+}
+
+impl DeploymentHook {
+    pub fn has_embedded_pod_template(&self) -> bool {
+        self.spec.template.has_embedded_pod_template()
+    }
+
+    pub fn get_pod_template_name(&self) -> Option<String> {
+        self.spec.template.name.clone()
+    }
+
+    pub async fn get_pod_template(
+        &self,
+        deployment: &impl Workload,
+        pod_template_service: PodTemplateService,
+    ) -> Result<PodTemplate, Box<dyn std::error::Error>> {
+        let namespace = self
+            .metadata
+            .namespace
+            .clone()
+            .unwrap_or_else(|| "default".to_string());
+
+        self.spec
+            .template
+            .get_pod_template(&namespace, deployment, pod_template_service)
+            .await
+    }
+
+    /// Check whether a deployment is selected by this hook. `namespace_labels` are the labels
+    /// of the deployment's namespace and are only consulted for hooks with a namespace selector.
+    pub fn does_match_deployment(
+        &self,
+        deployment: &impl Workload,
+        namespace_labels: &BTreeMap<String, String>,
+    ) -> bool {
+        if !self.does_match_namespace(&deployment.meta().namespace, namespace_labels) {
+            return false;
+        }
+
+        self.spec.selector.selects(deployment)
     }
 
-    pub fn does_match_deployment(&self, deployment: &Deployment) -> bool {
-        if let Some(ref labels) = deployment.metadata.labels {
-            self.spec
-                .selector
-                .labels
-                .iter()
-                .all(|hook_label| labels.get_key_value(hook_label.0) == Some(hook_label))
+    pub fn does_match_namespace(
+        &self,
+        namespace: &Option<String>,
+        namespace_labels: &BTreeMap<String, String>,
+    ) -> bool {
+        if let Some(ref namespace_selector) = self.spec.namespace_selector {
+            label_selector_matches(namespace_selector, namespace_labels)
         } else {
-            false
+            namespace.is_some() && *namespace == self.metadata.namespace
+        }
+    }
+}
+
+impl Hook for DeploymentHook {
+    fn pod_template(&self) -> &InternalPodTemplate {
+        &self.spec.template
+    }
+
+    fn hook_status(&self) -> Option<&DeploymentHookStatus> {
+        self.status.as_ref()
+    }
+
+    fn target_kind(&self) -> TargetKind {
+        self.spec.selector.target_kind
+    }
+
+    fn deployment_selector(&self) -> &DeploymentSelector {
+        &self.spec.selector
+    }
+
+    fn deployment_namespace(&self) -> Option<&str> {
+        match self.spec.namespace_selector {
+            Some(_) => None,
+            None => self.metadata.namespace.as_deref(),
         }
     }
+
+    fn rollout_phase(&self) -> RolloutPhase {
+        self.spec.phase
+    }
+
+    fn trigger(&self) -> HookTrigger {
+        self.spec.trigger
+    }
+
+    fn concurrency_policy(&self) -> ConcurrencyPolicy {
+        self.spec.concurrency_policy
+    }
+
+    fn suspended(&self) -> bool {
+        self.spec.suspend
+    }
+
+    fn resume_policy(&self) -> ResumePolicy {
+        self.spec.resume_policy
+    }
+
+    fn successful_jobs_history_limit(&self) -> Option<u32> {
+        self.spec.successful_jobs_history_limit
+    }
+
+    fn failed_jobs_history_limit(&self) -> Option<u32> {
+        self.spec.failed_jobs_history_limit
+    }
+
+    fn run_after(&self) -> &[String] {
+        &self.spec.run_after
+    }
+
+    fn does_match_deployment(
+        &self,
+        deployment: &impl Workload,
+        namespace_labels: &BTreeMap<String, String>,
+    ) -> bool {
+        DeploymentHook::does_match_deployment(self, deployment, namespace_labels)
+    }
+
+    fn job_namespace(&self, _deployment: &impl Workload) -> String {
+        self.metadata
+            .namespace
+            .clone()
+            .unwrap_or_else(|| "default".to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use k8s_openapi::api::apps::v1::{Deployment, StatefulSet};
+    use serde_json::json;
+
+    fn hook(namespace_selector: serde_json::Value) -> DeploymentHook {
+        serde_json::from_value(json!({
+            "apiVersion": "apps.mx.com/v1",
+            "kind": "DeploymentHook",
+            "metadata": { "name": "run-app-migrations", "namespace": "team-a" },
+            "spec": {
+                "selector": { "labels": { "app": "nginx" } },
+                "namespaceSelector": namespace_selector,
+                "template": { "name": "nginx-template" }
+            }
+        }))
+        .unwrap()
+    }
+
+    fn deployment(namespace: &str) -> Deployment {
+        serde_json::from_value(json!({
+            "metadata": {
+                "name": "nginx-deployment",
+                "namespace": namespace,
+                "labels": { "app": "nginx" }
+            }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn hooks_only_match_their_own_namespace_by_default() {
+        let hook = hook(serde_json::Value::Null);
+        let no_labels = BTreeMap::new();
+
+        assert!(hook.does_match_deployment(&deployment("team-a"), &no_labels));
+        assert!(!hook.does_match_deployment(&deployment("team-b"), &no_labels));
+    }
+
+    #[test]
+    fn deployments_are_listed_in_the_hook_namespace_with_the_hooks_label() {
+        let own_namespace = hook(serde_json::Value::Null);
+        assert_eq!(own_namespace.deployment_namespace(), Some("team-a"));
+        assert_eq!(
+            own_namespace.deployment_selector().list_selector(),
+            "apps.mx.com/deploymenthook,app=nginx"
+        );
+
+        let selected = hook(json!({ "matchLabels": { "platform/hooks": "enabled" } }));
+        assert_eq!(selected.deployment_namespace(), None);
+    }
+
+    #[test]
+    fn namespace_selector_opts_into_other_namespaces() {
+        let hook = hook(json!({ "matchLabels": { "platform/hooks": "enabled" } }));
+        let enabled = BTreeMap::from([("platform/hooks".to_string(), "enabled".to_string())]);
+
+        assert!(hook.does_match_deployment(&deployment("team-b"), &enabled));
+        assert!(!hook.does_match_deployment(&deployment("team-a"), &BTreeMap::new()));
+    }
+
+    #[tokio::test]
+    async fn deployments_of_other_namespaces_have_to_allow_from_deployment() {
+        let config = kube::Config::new("http://127.0.0.1:6443".parse().unwrap());
+        let pod_template_service = PodTemplateService::new(kube::Client::try_from(config).unwrap());
+        let template: InternalPodTemplate = serde_json::from_value(json!({
+            "fromDeployment": { "container": "nginx" }
+        }))
+        .unwrap();
+        let mut deployment: Deployment = serde_json::from_value(json!({
+            "metadata": { "name": "nginx-deployment", "namespace": "team-a" },
+            "spec": {
+                "selector": { "matchLabels": { "app": "nginx" } },
+                "template": { "spec": { "containers": [{ "name": "nginx" }] } }
+            }
+        }))
+        .unwrap();
+
+        let resolve = |namespace: &'static str, deployment: Deployment| {
+            let template = template.clone();
+            let pod_template_service = pod_template_service.clone();
+            async move {
+                template
+                    .get_pod_template(namespace, &deployment, pod_template_service)
+                    .await
+                    .map_err(|err| err.to_string())
+            }
+        };
+
+        assert!(resolve("team-a", deployment.clone()).await.is_ok());
+        assert!(resolve("platform", deployment.clone()).await.is_err());
+
+        deployment.metadata.annotations = Some(BTreeMap::from([(
+            ALLOWED_NAMESPACES_ANNOTATION.to_string(),
+            "platform".to_string(),
+        )]));
+        let pod_template = resolve("platform", deployment).await.unwrap();
+        assert_eq!(pod_template.metadata.namespace.as_deref(), Some("platform"));
+    }
+
+    #[test]
+    fn hooks_only_match_their_target_kind() {
+        let no_labels = BTreeMap::new();
+        let statefulset: StatefulSet = serde_json::from_value(json!({
+            "metadata": {
+                "name": "postgres",
+                "namespace": "team-a",
+                "labels": { "app": "nginx" }
+            }
+        }))
+        .unwrap();
+
+        let mut hook = hook(serde_json::Value::Null);
+        assert!(!hook.does_match_deployment(&statefulset, &no_labels));
+
+        hook.spec.selector.target_kind = TargetKind::StatefulSet;
+        assert!(hook.does_match_deployment(&statefulset, &no_labels));
+        assert!(!hook.does_match_deployment(&deployment("team-a"), &no_labels));
+    }
 }
@@ -0,0 +1,35 @@
+// Metrics of the caches in this crate, registered with the default Prometheus registry when
+// the `metrics` feature is enabled. Without it recording a metric does nothing.
+
+#[cfg(feature = "metrics")]
+use once_cell::sync::Lazy;
+#[cfg(feature = "metrics")]
+use prometheus::{register_int_counter, IntCounter};
+
+#[cfg(feature = "metrics")]
+static POD_TEMPLATE_CACHE_HITS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "docbot_pod_template_cache_hits_total",
+        "Pod templates served from the cache"
+    )
+    .unwrap()
+});
+
+#[cfg(feature = "metrics")]
+static POD_TEMPLATE_CACHE_MISSES: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "docbot_pod_template_cache_misses_total",
+        "Pod templates read from the api because they were not cached"
+    )
+    .unwrap()
+});
+
+pub(crate) fn pod_template_cache_hit() {
+    #[cfg(feature = "metrics")]
+    POD_TEMPLATE_CACHE_HITS.inc();
+}
+
+pub(crate) fn pod_template_cache_miss() {
+    #[cfg(feature = "metrics")]
+    POD_TEMPLATE_CACHE_MISSES.inc();
+}
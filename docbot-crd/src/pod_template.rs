@@ -1,11 +1,10 @@
+use crate::metrics;
 use futures::future::{self};
-use futures::TryStreamExt;
+use futures::Stream;
 use k8s_openapi::api::core::v1::PodTemplate;
-use kube::{
-    api::{ListParams, WatchEvent},
-    client::Client,
-    Api,
-};
+use kube::{api::ListParams, client::Client, Api};
+use kube_runtime::utils::StreamBackoff;
+use kube_runtime::watcher::{self, watcher};
 use lru::LruCache;
 use tokio::sync::broadcast::Sender;
 
@@ -74,9 +73,11 @@ impl PodTemplateService {
         // Check the LRU cache for the pod template.
         let cache_key = (namespace.to_string(), name.to_string());
         if let Some(pod_template) = locked.get(&cache_key) {
+            metrics::pod_template_cache_hit();
             return Ok(Some(pod_template.clone()));
         }
         info!("Cache miss: calling the api");
+        metrics::pod_template_cache_miss();
         // Otherwise we should pull directly from the API.
         let pod_template_api: Api<PodTemplate> = Api::namespaced(self.client.clone(), namespace);
         let pod_template = pod_template_api.get(name).await?;
@@ -87,6 +88,10 @@ impl PodTemplateService {
         Ok(Some(pod_template))
     }
 
+    pub(crate) fn client(&self) -> &Client {
+        &self.client
+    }
+
     pub async fn push(&self, pod_template: PodTemplate) {
         let namespace = pod_template
             .metadata
@@ -105,52 +110,84 @@ impl PodTemplateService {
         }
     }
 
-    pub async fn watch_for_changes(&self) -> Result<(), Box<dyn std::error::Error>> {
+    /// The changes of the pod templates, which keep the cache up to date through
+    /// `record_change`.
+    pub fn changes(
+        &self,
+    ) -> impl Stream<Item = Result<watcher::Event<PodTemplate>, watcher::Error>> + Send {
         let pod_template_api: Api<PodTemplate> = Api::all(self.client.clone());
-        let changes_channel = self.changes_channel.clone();
-
-        loop {
-            let lp = ListParams::default();
-            let pod_template_stream = pod_template_api.watch(&lp, "0").await?;
-            tokio::pin!(pod_template_stream);
-
-            while let Some(ref pod_template_event) = pod_template_stream.try_next().await? {
-                match pod_template_event {
-                    WatchEvent::Added(pod_template) | WatchEvent::Modified(pod_template) => {
-                        let name = pod_template
-                            .metadata
-                            .name
-                            .clone()
-                            .unwrap_or_else(|| "unknown".to_string());
-                        let namespace = pod_template
-                            .metadata
-                            .namespace
-                            .clone()
-                            .unwrap_or_else(|| "default".to_string());
-
-                        info!(
-                            "Witnessed {:?} for PodTemplate: {}/{}",
-                            pod_template_event, namespace, name
-                        );
-                        self.push(pod_template.clone()).await;
-
-                        if let Err(err) =
-                            changes_channel.send(format!("{}/{}", namespace.clone(), name.clone()))
-                        {
-                            info!("receiver count {}", changes_channel.receiver_count());
-                            //This happens a lot as not all events will have a receiver ready"
-                            warn!("Unable to publish a change to {namespace}/{name} over internal brodcast stream with error {}", 
-                            err);
-                        } else {
-                            info!(
-                                "Published event for {}",
-                                format!("{}/{}", namespace.clone(), name.clone())
-                            );
-                        }
+        StreamBackoff::new(
+            watcher(pod_template_api, ListParams::default()),
+            watcher::default_backoff(),
+        )
+    }
+
+    /// Cache the pod templates a change is about and let the hooks waiting for them know, or
+    /// evict the ones that were deleted.
+    pub async fn record_change(&self, event: watcher::Event<PodTemplate>) {
+        match event {
+            watcher::Event::Applied(pod_template) => self.record_applied(pod_template).await,
+            watcher::Event::Deleted(pod_template) => {
+                let cache_key = pod_template_key(&pod_template);
+                if self.cache.lock().await.pop(&cache_key).is_some() {
+                    info!(
+                        "Witnessed the deletion of PodTemplate: {}/{}",
+                        cache_key.0, cache_key.1
+                    );
+                }
+            }
+            // The pod templates deleted while we weren't watching are missing from the list.
+            watcher::Event::Restarted(pod_templates) => {
+                let listed: Vec<(String, String)> =
+                    pod_templates.iter().map(pod_template_key).collect();
+                {
+                    let mut locked = self.cache.lock().await;
+                    let deleted: Vec<(String, String)> = locked
+                        .iter()
+                        .map(|(cache_key, _)| cache_key)
+                        .filter(|cache_key| !listed.contains(cache_key))
+                        .cloned()
+                        .collect();
+                    for cache_key in deleted {
+                        locked.pop(&cache_key);
                     }
-                    _ => { /* ignore */ }
+                }
+                for pod_template in pod_templates {
+                    self.record_applied(pod_template).await;
                 }
             }
         }
     }
+
+    async fn record_applied(&self, pod_template: PodTemplate) {
+        let (namespace, name) = pod_template_key(&pod_template);
+
+        info!("Witnessed a change of PodTemplate: {}/{}", namespace, name);
+        self.push(pod_template).await;
+
+        if let Err(err) = self.changes_channel.send(format!("{namespace}/{name}")) {
+            info!("receiver count {}", self.changes_channel.receiver_count());
+            //This happens a lot as not all events will have a receiver ready"
+            warn!("Unable to publish a change to {namespace}/{name} over internal brodcast stream with error {}", 
+            err);
+        } else {
+            info!("Published event for {}", format!("{namespace}/{name}"));
+        }
+    }
+}
+
+/// The cache key of a pod template, defaulting the namespace the same way `push` does.
+fn pod_template_key(pod_template: &PodTemplate) -> (String, String) {
+    (
+        pod_template
+            .metadata
+            .namespace
+            .clone()
+            .unwrap_or_else(|| "default".to_string()),
+        pod_template
+            .metadata
+            .name
+            .clone()
+            .unwrap_or_else(|| "unknown".to_string()),
+    )
 }
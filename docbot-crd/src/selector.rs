@@ -0,0 +1,163 @@
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{LabelSelector, LabelSelectorRequirement};
+use std::collections::BTreeMap;
+use tracing::warn;
+
+/// Evaluate a set of `matchLabels` and `matchExpressions` against the labels of an object
+/// using the same semantics as a Kubernetes `LabelSelector`: every label and every expression
+/// has to match, and an empty selector matches everything.
+pub fn labels_match(
+    match_labels: &BTreeMap<String, String>,
+    match_expressions: &[LabelSelectorRequirement],
+    labels: &BTreeMap<String, String>,
+) -> bool {
+    match_labels
+        .iter()
+        .all(|(key, value)| labels.get(key) == Some(value))
+        && match_expressions
+            .iter()
+            .all(|requirement| requirement_matches(requirement, labels))
+}
+
+/// Evaluate a full Kubernetes `LabelSelector` against the labels of an object.
+pub fn label_selector_matches(selector: &LabelSelector, labels: &BTreeMap<String, String>) -> bool {
+    labels_match(
+        selector.match_labels.as_ref().unwrap_or(&BTreeMap::new()),
+        selector.match_expressions.as_deref().unwrap_or_default(),
+        labels,
+    )
+}
+
+/// Render `matchLabels` and `matchExpressions` in the syntax of the `labelSelector` list
+/// parameter, one requirement per element. Requirements that can't be expressed there, like
+/// unknown operators, are left out, so the result may select more than `labels_match`.
+pub fn selector_requirements(
+    match_labels: &BTreeMap<String, String>,
+    match_expressions: &[LabelSelectorRequirement],
+) -> Vec<String> {
+    match_labels
+        .iter()
+        .map(|(key, value)| format!("{key}={value}"))
+        .chain(match_expressions.iter().filter_map(requirement_query))
+        .collect()
+}
+
+fn requirement_query(requirement: &LabelSelectorRequirement) -> Option<String> {
+    let key = &requirement.key;
+    let values = requirement.values.as_deref().unwrap_or_default().join(",");
+
+    match requirement.operator.as_str() {
+        "In" if !values.is_empty() => Some(format!("{key} in ({values})")),
+        "NotIn" if !values.is_empty() => Some(format!("{key} notin ({values})")),
+        "Exists" => Some(key.clone()),
+        "DoesNotExist" => Some(format!("!{key}")),
+        _ => None,
+    }
+}
+
+fn requirement_matches(
+    requirement: &LabelSelectorRequirement,
+    labels: &BTreeMap<String, String>,
+) -> bool {
+    let values = requirement.values.as_deref().unwrap_or_default();
+    let value = labels.get(&requirement.key);
+
+    match requirement.operator.as_str() {
+        "In" => value.is_some_and(|value| values.contains(value)),
+        "NotIn" => !value.is_some_and(|value| values.contains(value)),
+        "Exists" => value.is_some(),
+        "DoesNotExist" => value.is_none(),
+        operator => {
+            warn!(
+                "Unknown label selector operator '{}' for key '{}', treating it as not matching",
+                operator, requirement.key
+            );
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn requirement(key: &str, operator: &str, values: &[&str]) -> LabelSelectorRequirement {
+        LabelSelectorRequirement {
+            key: key.to_string(),
+            operator: operator.to_string(),
+            values: Some(values.iter().map(|value| value.to_string()).collect()),
+        }
+    }
+
+    fn labels(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn match_expressions_operators() {
+        let api = labels(&[("tier", "api"), ("app", "nginx")]);
+        let cron = labels(&[("tier", "cron")]);
+
+        let in_ = [requirement("tier", "In", &["api", "worker"])];
+        assert!(labels_match(&BTreeMap::new(), &in_, &api));
+        assert!(!labels_match(&BTreeMap::new(), &in_, &cron));
+
+        let not_in = [requirement("tier", "NotIn", &["api", "worker"])];
+        assert!(!labels_match(&BTreeMap::new(), &not_in, &api));
+        assert!(labels_match(&BTreeMap::new(), &not_in, &cron));
+        assert!(labels_match(&BTreeMap::new(), &not_in, &BTreeMap::new()));
+
+        let exists = [requirement("app", "Exists", &[])];
+        assert!(labels_match(&BTreeMap::new(), &exists, &api));
+        assert!(!labels_match(&BTreeMap::new(), &exists, &cron));
+
+        let does_not_exist = [requirement("app", "DoesNotExist", &[])];
+        assert!(!labels_match(&BTreeMap::new(), &does_not_exist, &api));
+        assert!(labels_match(&BTreeMap::new(), &does_not_exist, &cron));
+
+        let unknown = [requirement("app", "Gt", &["1"])];
+        assert!(!labels_match(&BTreeMap::new(), &unknown, &api));
+    }
+
+    #[test]
+    fn requirements_in_list_parameter_syntax() {
+        let expressions = [
+            requirement("tier", "In", &["api", "worker"]),
+            requirement("track", "NotIn", &["canary"]),
+            requirement("team", "Exists", &[]),
+            requirement("legacy", "DoesNotExist", &[]),
+            requirement("replicas", "Gt", &["1"]),
+        ];
+
+        assert_eq!(
+            selector_requirements(&labels(&[("app", "nginx")]), &expressions),
+            vec![
+                "app=nginx",
+                "tier in (api,worker)",
+                "track notin (canary)",
+                "team",
+                "!legacy"
+            ]
+        );
+    }
+
+    #[test]
+    fn match_labels_and_expressions_are_combined() {
+        let match_labels = labels(&[("app", "nginx")]);
+        let expressions = [requirement("tier", "In", &["api"])];
+
+        assert!(labels_match(
+            &match_labels,
+            &expressions,
+            &labels(&[("tier", "api"), ("app", "nginx")])
+        ));
+        assert!(!labels_match(
+            &match_labels,
+            &expressions,
+            &labels(&[("tier", "api"), ("app", "redis")])
+        ));
+        assert!(labels_match(&BTreeMap::new(), &[], &BTreeMap::new()));
+    }
+}
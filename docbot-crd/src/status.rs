@@ -0,0 +1,371 @@
+use crate::TargetKind;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
+use k8s_openapi::chrono::{Duration, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// Condition type that is `True` while the most recent hook job has not finished yet.
+pub const CONDITION_PROGRESSING: &str = "Progressing";
+
+/// Condition type that reflects whether the most recent hook job finished successfully.
+pub const CONDITION_SUCCEEDED: &str = "Succeeded";
+
+/// Condition type that is `True` while a `PreRollout` hook is holding a deployment paused.
+pub const CONDITION_ROLLOUT_BLOCKED: &str = "RolloutBlocked";
+
+/// Condition type that is `True` when a failed job of the hook rolled the deployment back.
+pub const CONDITION_ROLLED_BACK: &str = "RolledBack";
+
+/// Condition type that is `True` while the spec asks for something the hook can't do, e.g.
+/// `trigger: OnFailure` on a kind of workload whose rollouts never fail.
+pub const CONDITION_INVALID_SPEC: &str = "InvalidSpec";
+
+/// How long a run whose retries were exhausted stays in the status, in seconds.
+pub const RETRY_HISTORY_SECONDS: i64 = 3600;
+
+/// Struct corresponding to the `status` subresource of the `DeploymentHook` resource. It is
+/// only ever written by the controller and records the most recent run of the hook.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct DeploymentHookStatus {
+    /// The `namespace/name` of the deployment that last triggered this hook.
+    pub last_deployment: Option<String>,
+    /// The pod template hash of the deployment revision that last triggered this hook.
+    pub last_pod_template_hash: Option<String>,
+    /// The name of the most recent job created for this hook.
+    pub last_job_name: Option<String>,
+    pub phase: Option<HookPhase>,
+    pub last_triggered_time: Option<Time>,
+    pub last_completion_time: Option<Time>,
+    // Always serialized, even when empty, so clearing the last condition reaches the api server
+    // through a merge patch.
+    #[serde(default)]
+    pub conditions: Vec<HookCondition>,
+    /// Runs whose job could not be created yet, the latest one per deployment. Runs that were
+    /// given up on are kept for `RETRY_HISTORY_SECONDS` after their last attempt.
+    #[serde(default)]
+    pub retries: Vec<JobCreationRetry>,
+    /// The latest run per deployment. Hooks running after this one are ordered on these, so
+    /// rollouts of several deployments at the same time don't overwrite each other's run.
+    #[serde(default)]
+    pub runs: Vec<HookRun>,
+    /// The last value of the run-now annotation the controller acted on.
+    pub handled_run_now_token: Option<String>,
+    /// Rollouts that matched the hook while it was suspended, the latest one per deployment.
+    #[serde(default)]
+    pub suspended_runs: Vec<SuspendedRun>,
+}
+
+/// A run of the hook for a rollout of a deployment.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct HookRun {
+    pub deployment_kind: TargetKind,
+    /// The `namespace/name` of the deployment that rolled out.
+    pub deployment: String,
+    pub pod_template_hash: Option<String>,
+    /// The name of the job created for the run, unset until it is created.
+    pub job_name: Option<String>,
+    pub phase: Option<HookPhase>,
+    /// When the rollout was noticed and the run claimed.
+    pub triggered_time: Option<Time>,
+}
+
+impl HookRun {
+    /// Whether the run is for the rollout of the given deployment to the given pod template.
+    pub fn is_for(&self, deployment_kind: TargetKind, deployment: &str, hash: &str) -> bool {
+        self.deployment_kind == deployment_kind
+            && self.deployment == deployment
+            && self.pod_template_hash.as_deref() == Some(hash)
+    }
+}
+
+/// A rollout the hook would have run for if it hadn't been suspended.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SuspendedRun {
+    pub deployment_kind: TargetKind,
+    /// The `namespace/name` of the deployment that rolled out.
+    pub deployment: String,
+    pub pod_template_hash: Option<String>,
+    pub rollout_time: Option<Time>,
+}
+
+/// A run of the hook whose job could not be created yet. It is kept in the status so the
+/// controller can pick the retries up again after a restart.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct JobCreationRetry {
+    pub deployment_kind: TargetKind,
+    /// The `namespace/name` of the deployment that triggered the run.
+    pub deployment: String,
+    pub pod_template_hash: Option<String>,
+    /// Number of failed attempts so far.
+    pub attempts: u32,
+    pub last_error: String,
+    pub last_attempt_time: Option<Time>,
+    /// Unset once the attempts are exhausted.
+    pub next_attempt_time: Option<Time>,
+}
+
+impl JobCreationRetry {
+    /// Whether the retry is for a rollout of the given deployment, whichever pod template it
+    /// rolled out.
+    pub fn is_for(&self, deployment_kind: TargetKind, deployment: &str) -> bool {
+        self.deployment_kind == deployment_kind && self.deployment == deployment
+    }
+}
+
+/// The lifecycle of the most recent job created for a hook.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, JsonSchema)]
+pub enum HookPhase {
+    Pending,
+    Running,
+    Succeeded,
+    Failed,
+}
+
+impl HookPhase {
+    pub fn is_finished(&self) -> bool {
+        matches!(self, HookPhase::Succeeded | HookPhase::Failed)
+    }
+}
+
+/// A standard Kubernetes style condition. The `meta/v1` `Condition` type is not available in
+/// the api version we build against, so this mirrors its shape.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct HookCondition {
+    #[serde(rename = "type")]
+    pub type_: String,
+    /// One of `True`, `False` or `Unknown`.
+    pub status: String,
+    pub reason: Option<String>,
+    pub message: Option<String>,
+    pub last_transition_time: Option<Time>,
+}
+
+impl HookCondition {
+    pub fn new(type_: &str, status: bool, reason: &str, message: String) -> Self {
+        Self {
+            type_: type_.to_string(),
+            status: if status { "True" } else { "False" }.to_string(),
+            reason: Some(reason.to_string()),
+            message: Some(message),
+            last_transition_time: Some(Time(Utc::now())),
+        }
+    }
+
+    pub fn is_true(&self) -> bool {
+        self.status == "True"
+    }
+}
+
+impl DeploymentHookStatus {
+    pub fn condition(&self, type_: &str) -> Option<&HookCondition> {
+        self.conditions
+            .iter()
+            .find(|condition| condition.type_ == type_)
+    }
+
+    /// Adds or replaces the condition of the same type. The transition time is only moved
+    /// forward when the status of the condition actually changes.
+    pub fn set_condition(&mut self, mut condition: HookCondition) {
+        if let Some(existing) = self
+            .conditions
+            .iter_mut()
+            .find(|existing| existing.type_ == condition.type_)
+        {
+            if existing.status == condition.status {
+                condition.last_transition_time = existing.last_transition_time.clone();
+            }
+            *existing = condition;
+        } else {
+            self.conditions.push(condition);
+        }
+    }
+
+    /// The run for the rollout of the given deployment to the given pod template, if the
+    /// latest run for the deployment is that one.
+    pub fn run_for(
+        &self,
+        deployment_kind: TargetKind,
+        deployment: &str,
+        hash: &str,
+    ) -> Option<&HookRun> {
+        self.runs
+            .iter()
+            .find(|run| run.is_for(deployment_kind, deployment, hash))
+    }
+
+    /// Record a run, replacing the earlier run for the same deployment.
+    pub fn set_run(&mut self, run: HookRun) {
+        self.runs.retain(|existing| {
+            existing.deployment_kind != run.deployment_kind || existing.deployment != run.deployment
+        });
+        self.runs.push(run);
+    }
+
+    /// The pending retry of a rollout of the given deployment, if any.
+    pub fn retry_for(
+        &self,
+        deployment_kind: TargetKind,
+        deployment: &str,
+    ) -> Option<&JobCreationRetry> {
+        self.retries
+            .iter()
+            .find(|retry| retry.is_for(deployment_kind, deployment))
+    }
+
+    /// Record a failed attempt, replacing the retry for the same deployment, which is either an
+    /// earlier attempt of the same run or a run the new one superseded.
+    pub fn record_retry(&mut self, retry: JobCreationRetry) {
+        self.clear_retry(retry.deployment_kind, &retry.deployment);
+        self.retries.push(retry);
+    }
+
+    /// Forget the retry for a deployment, e.g. because its job was created, and the runs given
+    /// up on more than `RETRY_HISTORY_SECONDS` ago.
+    pub fn clear_retry(&mut self, deployment_kind: TargetKind, deployment: &str) {
+        let expired_before = Utc::now() - Duration::seconds(RETRY_HISTORY_SECONDS);
+        self.retries.retain(|retry| {
+            let expired = retry.next_attempt_time.is_none()
+                && !matches!(&retry.last_attempt_time, Some(Time(time)) if *time >= expired_before);
+            !expired && !retry.is_for(deployment_kind, deployment)
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn set_condition_keeps_transition_time_when_status_is_unchanged() {
+        let mut status = DeploymentHookStatus::default();
+        let mut first = HookCondition::new(CONDITION_SUCCEEDED, false, "JobCreated", "a".into());
+        first.last_transition_time = None;
+        status.set_condition(first);
+
+        status.set_condition(HookCondition::new(
+            CONDITION_SUCCEEDED,
+            false,
+            "JobRunning",
+            "b".into(),
+        ));
+        let condition = status.condition(CONDITION_SUCCEEDED).unwrap();
+        assert_eq!(condition.reason.as_deref(), Some("JobRunning"));
+        assert_eq!(condition.last_transition_time, None);
+
+        status.set_condition(HookCondition::new(
+            CONDITION_SUCCEEDED,
+            true,
+            "JobSucceeded",
+            "c".into(),
+        ));
+        let condition = status.condition(CONDITION_SUCCEEDED).unwrap();
+        assert!(condition.is_true());
+        assert!(condition.last_transition_time.is_some());
+        assert_eq!(status.conditions.len(), 1);
+    }
+
+    #[test]
+    fn empty_lists_are_serialized_so_merge_patches_clear_them() {
+        let status = serde_json::to_value(DeploymentHookStatus::default()).unwrap();
+        assert_eq!(status["conditions"], serde_json::json!([]));
+        assert_eq!(status["retries"], serde_json::json!([]));
+    }
+
+    #[test]
+    fn runs_are_kept_per_deployment() {
+        let run = |deployment: &str, hash: &str| HookRun {
+            deployment_kind: TargetKind::Deployment,
+            deployment: deployment.into(),
+            pod_template_hash: Some(hash.into()),
+            job_name: None,
+            phase: Some(HookPhase::Pending),
+            triggered_time: None,
+        };
+
+        let mut status = DeploymentHookStatus::default();
+        status.set_run(run("docbot-test/nginx", "a"));
+        status.set_run(run("docbot-test/postgres", "b"));
+        assert!(status
+            .run_for(TargetKind::Deployment, "docbot-test/nginx", "a")
+            .is_some());
+        assert!(status
+            .run_for(TargetKind::Deployment, "docbot-test/postgres", "b")
+            .is_some());
+
+        // A newer rollout of the same deployment replaces its run.
+        status.set_run(run("docbot-test/nginx", "c"));
+        assert!(status
+            .run_for(TargetKind::Deployment, "docbot-test/nginx", "a")
+            .is_none());
+        assert!(status
+            .run_for(TargetKind::StatefulSet, "docbot-test/nginx", "c")
+            .is_none());
+        assert_eq!(status.runs.len(), 2);
+    }
+
+    fn retry(deployment: &str, hash: &str, minutes_ago: i64, exhausted: bool) -> JobCreationRetry {
+        let last_attempt_time = Utc::now() - Duration::minutes(minutes_ago);
+        JobCreationRetry {
+            deployment_kind: TargetKind::Deployment,
+            deployment: deployment.into(),
+            pod_template_hash: Some(hash.into()),
+            attempts: 1,
+            last_error: "throttled".into(),
+            last_attempt_time: Some(Time(last_attempt_time)),
+            next_attempt_time: (!exhausted).then(|| Time(last_attempt_time + Duration::seconds(5))),
+        }
+    }
+
+    #[test]
+    fn retries_are_kept_per_deployment() {
+        let mut status = DeploymentHookStatus::default();
+        status.record_retry(retry("docbot-test/nginx", "a", 0, false));
+        status.record_retry(retry("docbot-test/postgres", "b", 0, false));
+        assert_eq!(status.retries.len(), 2);
+
+        // A newer rollout of the same deployment supersedes its retry.
+        status.record_retry(retry("docbot-test/nginx", "c", 0, false));
+        let hashes: Vec<_> = status
+            .retries
+            .iter()
+            .map(|retry| retry.pod_template_hash.as_deref().unwrap())
+            .collect();
+        assert_eq!(hashes, vec!["b", "c"]);
+
+        status.clear_retry(TargetKind::Deployment, "docbot-test/postgres");
+        assert!(status
+            .retry_for(TargetKind::Deployment, "docbot-test/postgres")
+            .is_none());
+        assert!(status
+            .retry_for(TargetKind::Deployment, "docbot-test/nginx")
+            .is_some());
+    }
+
+    #[test]
+    fn exhausted_retries_expire_on_their_own() {
+        let mut status = DeploymentHookStatus::default();
+        status.record_retry(retry("docbot-test/nginx", "a", 120, true));
+        status.record_retry(retry("docbot-test/redis", "b", 10, true));
+        status.record_retry(retry("docbot-test/envoy", "c", 120, false));
+        status.record_retry(retry("docbot-test/postgres", "d", 0, false));
+
+        let deployments: Vec<_> = status
+            .retries
+            .iter()
+            .map(|retry| retry.deployment.as_str())
+            .collect();
+        assert_eq!(
+            deployments,
+            vec![
+                "docbot-test/redis",
+                "docbot-test/envoy",
+                "docbot-test/postgres"
+            ]
+        );
+    }
+}
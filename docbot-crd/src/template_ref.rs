@@ -0,0 +1,143 @@
+use crate::selector::labels_match;
+use k8s_openapi::api::core::v1::Namespace;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+use kube::{client::Client, Api};
+use std::collections::BTreeMap;
+
+/// Comma separated namespaces whose hooks may use a template from another namespace, `*`
+/// allows every namespace.
+pub const ALLOWED_NAMESPACES_ANNOTATION: &str = "apps.mx.com/allowed-namespaces";
+
+/// Equality based label selector, e.g. `team=payments,env=prod`, on the namespaces whose hooks
+/// may use a template from another namespace.
+pub const ALLOWED_NAMESPACE_SELECTOR_ANNOTATION: &str = "apps.mx.com/allowed-namespace-selector";
+
+/// Split a template reference into the namespace and name of the template. Plain names
+/// refer to a template in `namespace`.
+pub fn parse_template_reference<'a>(reference: &'a str, namespace: &'a str) -> (&'a str, &'a str) {
+    reference.split_once('/').unwrap_or((namespace, reference))
+}
+
+fn annotation<'a>(template: &'a ObjectMeta, key: &str) -> Option<&'a str> {
+    template.annotations.as_ref()?.get(key).map(String::as_str)
+}
+
+/// Fail unless the template whose metadata is `template` allows jobs in `namespace` to use
+/// it. The labels of the namespace are only looked up for templates with a selector.
+pub async fn ensure_allowed(
+    client: &Client,
+    template: &ObjectMeta,
+    namespace: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let namespace_labels = if annotation(template, ALLOWED_NAMESPACE_SELECTOR_ANNOTATION).is_some()
+    {
+        let namespace_api: Api<Namespace> = Api::all(client.clone());
+        namespace_api
+            .get(namespace)
+            .await?
+            .metadata
+            .labels
+            .unwrap_or_default()
+    } else {
+        BTreeMap::new()
+    };
+
+    if allows_namespace(template, namespace, &namespace_labels) {
+        Ok(())
+    } else {
+        Err(format!(
+            "{}/{} does not allow namespace {namespace} to use it",
+            template.namespace.as_deref().unwrap_or_default(),
+            template.name.as_deref().unwrap_or_default()
+        )
+        .into())
+    }
+}
+
+/// Whether the owner of `template` allows jobs in `namespace` to use it. Templates without
+/// either annotation can only be used from their own namespace, and a malformed selector
+/// allows nothing.
+fn allows_namespace(
+    template: &ObjectMeta,
+    namespace: &str,
+    namespace_labels: &BTreeMap<String, String>,
+) -> bool {
+    if template.namespace.as_deref() == Some(namespace) {
+        return true;
+    }
+
+    let allowed_by_name = annotation(template, ALLOWED_NAMESPACES_ANNOTATION).is_some_and(|list| {
+        list.split(',')
+            .map(str::trim)
+            .any(|allowed| allowed == "*" || allowed == namespace)
+    });
+
+    let allowed_by_selector = annotation(template, ALLOWED_NAMESPACE_SELECTOR_ANNOTATION)
+        .and_then(parse_selector)
+        .is_some_and(|selector| labels_match(&selector, &[], namespace_labels));
+
+    allowed_by_name || allowed_by_selector
+}
+
+fn parse_selector(selector: &str) -> Option<BTreeMap<String, String>> {
+    selector
+        .split(',')
+        .map(|requirement| {
+            let (key, value) = requirement.split_once('=')?;
+            let (key, value) = (key.trim(), value.trim());
+            (!key.is_empty()).then(|| (key.to_string(), value.to_string()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn template(annotations: serde_json::Value) -> ObjectMeta {
+        serde_json::from_value(json!({
+            "name": "db-migrator",
+            "namespace": "platform",
+            "annotations": annotations,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn parses_references() {
+        assert_eq!(
+            parse_template_reference("platform/db-migrator", "payments"),
+            ("platform", "db-migrator")
+        );
+        assert_eq!(
+            parse_template_reference("db-migrator", "payments"),
+            ("payments", "db-migrator")
+        );
+    }
+
+    #[test]
+    fn templates_decide_which_namespaces_use_them() {
+        let payments: BTreeMap<String, String> =
+            [("team".to_string(), "payments".to_string())].into();
+        let no_labels = BTreeMap::new();
+
+        let private = template(json!({}));
+        assert!(allows_namespace(&private, "platform", &no_labels));
+        assert!(!allows_namespace(&private, "payments", &payments));
+
+        let listed = template(json!({ ALLOWED_NAMESPACES_ANNOTATION: "billing, payments" }));
+        assert!(allows_namespace(&listed, "payments", &no_labels));
+        assert!(!allows_namespace(&listed, "search", &no_labels));
+
+        let everyone = template(json!({ ALLOWED_NAMESPACES_ANNOTATION: "*" }));
+        assert!(allows_namespace(&everyone, "search", &no_labels));
+
+        let selected = template(json!({ ALLOWED_NAMESPACE_SELECTOR_ANNOTATION: "team=payments" }));
+        assert!(allows_namespace(&selected, "payments-staging", &payments));
+        assert!(!allows_namespace(&selected, "search", &no_labels));
+
+        let malformed = template(json!({ ALLOWED_NAMESPACE_SELECTOR_ANNOTATION: "team" }));
+        assert!(!allows_namespace(&malformed, "payments", &payments));
+    }
+}
@@ -0,0 +1,147 @@
+use k8s_openapi::api::apps::v1::{DaemonSet, Deployment, StatefulSet};
+use k8s_openapi::api::core::v1::PodTemplateSpec;
+use kube::core::{ApiResource, DynamicObject, GroupVersionKind};
+use kube::Resource;
+use schemars::JsonSchema;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::fmt::Debug;
+
+/// The kinds of workload whose rollouts can trigger hooks.
+#[derive(
+    Serialize, Deserialize, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, JsonSchema,
+)]
+pub enum TargetKind {
+    #[default]
+    Deployment,
+    StatefulSet,
+    DaemonSet,
+    /// An Argo Rollouts `Rollout`, read as a dynamic object so the controller doesn't depend on
+    /// the Argo types.
+    Rollout,
+}
+
+impl TargetKind {
+    pub const ALL: [TargetKind; 4] = [
+        TargetKind::Deployment,
+        TargetKind::StatefulSet,
+        TargetKind::DaemonSet,
+        TargetKind::Rollout,
+    ];
+
+    pub fn api_version(&self) -> &'static str {
+        match self {
+            TargetKind::Rollout => "argoproj.io/v1alpha1",
+            _ => "apps/v1",
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            TargetKind::Deployment => "Deployment",
+            TargetKind::StatefulSet => "StatefulSet",
+            TargetKind::DaemonSet => "DaemonSet",
+            TargetKind::Rollout => "Rollout",
+        }
+    }
+
+    /// Whether rollouts of this kind can fail, which `OnFailure` hooks rely on. StatefulSets and
+    /// DaemonSets have no progress deadline, so their rollouts only ever stall.
+    pub fn reports_failed_rollouts(&self) -> bool {
+        matches!(self, TargetKind::Deployment | TargetKind::Rollout)
+    }
+
+    pub fn from_kind(kind: &str) -> Option<TargetKind> {
+        TargetKind::ALL
+            .into_iter()
+            .find(|target_kind| target_kind.kind() == kind)
+    }
+}
+
+/// The api resource of the Argo Rollouts `Rollout` custom resource.
+pub fn rollout_api_resource() -> ApiResource {
+    ApiResource::from_gvk(&GroupVersionKind::gvk(
+        "argoproj.io",
+        "v1alpha1",
+        TargetKind::Rollout.kind(),
+    ))
+}
+
+/// A workload that rolls out a pod template, which is all a hook needs to know about the
+/// object that triggered it.
+pub trait Workload: Resource + Clone + Debug + DeserializeOwned + Send + Sync + 'static {
+    fn target_kind(&self) -> TargetKind;
+
+    fn pod_template(&self) -> Option<PodTemplateSpec>;
+}
+
+impl Workload for Deployment {
+    fn target_kind(&self) -> TargetKind {
+        TargetKind::Deployment
+    }
+
+    fn pod_template(&self) -> Option<PodTemplateSpec> {
+        self.spec.as_ref().map(|spec| spec.template.clone())
+    }
+}
+
+impl Workload for StatefulSet {
+    fn target_kind(&self) -> TargetKind {
+        TargetKind::StatefulSet
+    }
+
+    fn pod_template(&self) -> Option<PodTemplateSpec> {
+        self.spec.as_ref().map(|spec| spec.template.clone())
+    }
+}
+
+impl Workload for DaemonSet {
+    fn target_kind(&self) -> TargetKind {
+        TargetKind::DaemonSet
+    }
+
+    fn pod_template(&self) -> Option<PodTemplateSpec> {
+        self.spec.as_ref().map(|spec| spec.template.clone())
+    }
+}
+
+/// Dynamic objects are only ever used for Argo Rollouts.
+impl Workload for DynamicObject {
+    fn target_kind(&self) -> TargetKind {
+        TargetKind::Rollout
+    }
+
+    fn pod_template(&self) -> Option<PodTemplateSpec> {
+        serde_json::from_value(self.data["spec"]["template"].clone()).ok()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn reads_the_pod_template_of_a_rollout() {
+        let rollout: DynamicObject = serde_json::from_value(json!({
+            "apiVersion": "argoproj.io/v1alpha1",
+            "kind": "Rollout",
+            "metadata": { "name": "nginx-rollout", "namespace": "docbot-test" },
+            "spec": {
+                "template": {
+                    "spec": { "containers": [{ "name": "nginx", "image": "nginx:1.14.2" }] }
+                }
+            }
+        }))
+        .unwrap();
+
+        let template = rollout.pod_template().unwrap();
+        assert_eq!(
+            template.spec.unwrap().containers[0].image.as_deref(),
+            Some("nginx:1.14.2")
+        );
+        assert_eq!(
+            TargetKind::from_kind(rollout.types.unwrap().kind.as_str()),
+            Some(TargetKind::Rollout)
+        );
+    }
+}